@@ -0,0 +1,230 @@
+//! Load test: slow uploads in flight while other clients list and download
+//!
+//! Starts a server on a temporary directory, streams `uploads` uploads of
+//! `size` MiB each over `seconds` seconds, and meanwhile has `readers` clients
+//! list the files and download one in a loop. Reads must keep completing while
+//! the uploads stream in, or the run fails.
+//!
+//! A benchmark to run by hand, concurrent database access is tested in
+//! `tests/concurrent_db.rs`.
+//!
+//! ```sh
+//! cargo run --release --example load_test -- [uploads] [size] [seconds] [readers]
+//! ```
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use localshare::{
+    config::{BindAddr, Config, Overrides},
+    server::Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+};
+
+const MIB: usize = 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+/// Size of the file the readers download
+const SEED_SIZE: usize = MIB;
+/// Slowest read that still counts as not blocked
+const MAX_READ_LATENCY: Duration = Duration::from_secs(1);
+
+struct Options {
+    uploads: usize,
+    size: usize,
+    seconds: u64,
+    readers: usize,
+}
+
+impl Options {
+    fn from_args() -> anyhow::Result<Self> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let arg = |i: usize, default: u64| -> anyhow::Result<u64> {
+            args.get(i)
+                .map(|a| a.parse().context(format!("invalid argument '{}'", a)))
+                .unwrap_or(Ok(default))
+        };
+        Ok(Self {
+            uploads: arg(0, 16)? as usize,
+            size: arg(1, 8)? as usize,
+            seconds: arg(2, 5)?,
+            readers: arg(3, 16)? as usize,
+        })
+    }
+}
+
+#[rocket::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::new()
+        .parse_env(env_logger::Env::new().default_filter_or("warn"))
+        .init();
+    let options = Options::from_args()?;
+
+    let workdir = std::env::temp_dir().join(format!("localshare-load-{}", std::process::id()));
+    std::fs::create_dir_all(&workdir)?;
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut config = Config::default();
+    config.network.bind = vec![BindAddr::Socket(addr)];
+    let server = Server::new(&workdir, config, Overrides::default())?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let serving = tokio::spawn(server.launch(async {
+        stopped.await.ok();
+    }));
+
+    let result = run(addr, &options).await;
+    stop.send(()).ok();
+    serving.await??;
+    std::fs::remove_dir_all(&workdir).ok();
+    result
+}
+
+async fn run(addr: SocketAddr, options: &Options) -> anyhow::Result<()> {
+    wait_until_up(addr).await?;
+    let seed = upload(addr, SEED_SIZE, Duration::ZERO).await?;
+    let id = seed
+        .split("\"id\":\"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .context("upload response has no id")?
+        .to_string();
+
+    let mut idle = Vec::new();
+    for _ in 0..50 {
+        idle.push(timed(get(addr, "/api/v1/list")).await?);
+    }
+
+    println!(
+        "{} uploads of {} MiB over {}s, {} readers",
+        options.uploads, options.size, options.seconds, options.readers
+    );
+    let started = Instant::now();
+    let pace = Duration::from_secs(options.seconds) / (options.size * MIB / CHUNK) as u32;
+    let mut uploads = JoinSet::new();
+    for _ in 0..options.uploads {
+        let size = options.size * MIB;
+        uploads.spawn(async move { upload(addr, size, pace).await });
+    }
+    let (done, done_rx) = tokio::sync::watch::channel(false);
+    let mut readers = JoinSet::new();
+    for n in 0..options.readers {
+        let path = match n % 2 {
+            0 => "/api/v1/list".to_string(),
+            _ => format!("/api/v1/download/{}", id),
+        };
+        let done = done_rx.clone();
+        readers.spawn(async move {
+            let mut latencies = Vec::new();
+            while !*done.borrow() {
+                latencies.push(timed(get(addr, &path)).await?);
+            }
+            anyhow::Ok(latencies)
+        });
+    }
+    while let Some(upload) = uploads.join_next().await {
+        upload??;
+    }
+    let upload_time = started.elapsed();
+    done.send(true).ok();
+    let mut busy = Vec::new();
+    while let Some(reader) = readers.join_next().await {
+        busy.extend(reader??);
+    }
+
+    let megabytes = options.uploads * options.size;
+    println!(
+        "uploads:  {} MiB in {:.2}s, {:.1} MiB/s",
+        megabytes,
+        upload_time.as_secs_f64(),
+        megabytes as f64 / upload_time.as_secs_f64()
+    );
+    report("idle", &mut idle);
+    report("loaded", &mut busy);
+    let slowest = busy.iter().max().copied().unwrap_or_default();
+    if slowest > MAX_READ_LATENCY {
+        anyhow::bail!(
+            "a read took {:?} while uploads were in flight, limit is {:?}",
+            slowest,
+            MAX_READ_LATENCY
+        );
+    }
+    println!("ok: reads were not blocked by uploads");
+    Ok(())
+}
+
+fn report(label: &str, latencies: &mut [Duration]) {
+    latencies.sort();
+    let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+    println!(
+        "{:8}  {} reads, p50 {:.1?}, p99 {:.1?}, max {:.1?}",
+        format!("{}:", label),
+        latencies.len(),
+        at(0.5),
+        at(0.99),
+        at(1.0)
+    );
+}
+
+async fn timed(request: impl Future<Output = anyhow::Result<String>>) -> anyhow::Result<Duration> {
+    let started = Instant::now();
+    request.await?;
+    Ok(started.elapsed())
+}
+
+async fn wait_until_up(addr: SocketAddr) -> anyhow::Result<()> {
+    for _ in 0..100 {
+        if get(addr, "/api/v1/info").await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    anyhow::bail!("server at {} did not come up", addr)
+}
+
+async fn get(addr: SocketAddr, path: &str) -> anyhow::Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let head = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+    stream.write_all(head.as_bytes()).await?;
+    response(stream).await
+}
+
+/// Uploads `size` bytes, sleeping `pace` between chunks to act as a slow client
+async fn upload(addr: SocketAddr, size: usize, pace: Duration) -> anyhow::Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let head = format!(
+        "POST /api/v1/upload?author=load&filename=load.bin HTTP/1.1\r\n\
+         Host: {}\r\nConnection: close\r\nContent-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\n\r\n",
+        addr, size
+    );
+    stream.write_all(head.as_bytes()).await?;
+    let chunk = vec![0x5a; CHUNK];
+    let mut sent = 0;
+    while sent < size {
+        let n = CHUNK.min(size - sent);
+        stream.write_all(&chunk[..n]).await?;
+        sent += n;
+        if !pace.is_zero() {
+            tokio::time::sleep(pace).await;
+        }
+    }
+    response(stream).await
+}
+
+/// Reads the whole response, failing unless the status is 2xx
+async fn response(mut stream: TcpStream) -> anyhow::Result<String> {
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).await?;
+    let text = String::from_utf8_lossy(&raw);
+    let status = text.split(' ').nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        anyhow::bail!("request failed: {}", text.lines().next().unwrap_or_default());
+    }
+    let body = text.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default();
+    Ok(body.to_string())
+}
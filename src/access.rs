@@ -0,0 +1,219 @@
+//! This module keeps clients outside the allowed networks away, see `access`
+//!
+//! The server listens on every interface by default, so it is reachable from
+//! any network the host joins. Once `access` lists anything, only clients in
+//! `access.allow`, on the subnets of `access.interfaces` or, with
+//! `access.local_subnet`, on the subnet of the address [`get_local_ip`] picks
+//! are served. Everyone else gets `403 Forbidden` before any route runs.
+//! Loopback clients are always served. Behind a local reverse proxy the
+//! client is the one named by `X-Real-IP`, see [`crate::client`].
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use rocket::{
+    Data, Request,
+    fairing::{Fairing, Info, Kind},
+};
+
+use crate::{
+    client::client_ip,
+    config::{AccessConfig, NetworkConfig},
+    ratelimit::{Refusal, refuse},
+    server::SharedServer,
+    utils::{get_local_ip, list_local_subnets},
+};
+
+/// How long the subnets of the interfaces are reused before they are read again
+const SUBNETS_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Subnets {
+    read_at: Instant,
+    /// the settings they were derived from
+    config: AccessConfig,
+    nets: Vec<IpNet>,
+}
+
+/// Access control fairing, shared by every listening socket
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl(Arc<Mutex<Option<Subnets>>>);
+
+impl AccessControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subnets allowed through `interfaces` and `local_subnet`
+    fn subnets(&self, config: &AccessConfig, network: &NetworkConfig) -> Vec<IpNet> {
+        let mut cached = self.0.lock().unwrap();
+        if let Some(subnets) = cached.as_ref()
+            && subnets.read_at.elapsed() < SUBNETS_TTL
+            && subnets.config == *config
+        {
+            return subnets.nets.clone();
+        }
+        let local = list_local_subnets();
+        let mut nets: Vec<IpNet> = local
+            .iter()
+            .filter(|subnet| config.interfaces.contains(&subnet.interface))
+            .map(|subnet| subnet.net)
+            .collect();
+        if config.local_subnet {
+            match get_local_ip(network) {
+                Some(ip) => match local.iter().find(|subnet| subnet.ip == ip) {
+                    Some(subnet) => nets.push(subnet.net),
+                    None => log::warn!("access: {} is not an interface address, its subnet is unknown", ip),
+                },
+                None => log::warn!("access: no local address, its subnet is unknown"),
+            }
+        }
+        *cached = Some(Subnets {
+            read_at: Instant::now(),
+            config: config.clone(),
+            nets: nets.clone(),
+        });
+        nets
+    }
+
+    fn allows(&self, client: IpAddr, config: &AccessConfig, network: &NetworkConfig) -> bool {
+        if client.is_loopback() || config.allow.iter().any(|range| range.contains(client)) {
+            return true;
+        }
+        if config.interfaces.is_empty() && !config.local_subnet {
+            return false;
+        }
+        self.subnets(config, network).iter().any(|net| net.contains(&client))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AccessControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access control",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let Some(server) = request.rocket().state::<SharedServer>() else {
+            return;
+        };
+        let config = server.access();
+        if !config.is_restricted() {
+            return;
+        }
+        // a client that can not be told apart can not be allowed
+        let allowed = client_ip(request)
+            .is_some_and(|client| self.allows(client, &config, server.network()));
+        if !allowed {
+            log::info!(
+                "access: refused {} {} from {}",
+                request.method(),
+                request.uri().path(),
+                client_ip(request).map_or_else(|| "unknown client".to_string(), |ip| ip.to_string())
+            );
+            refuse(request, Refusal::Denied);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// Restricted to a made up interface, so only `allow` and cached subnets match
+    fn config(allow: &[&str]) -> AccessConfig {
+        AccessConfig {
+            allow: allow.iter().map(|a| a.parse().unwrap()).collect(),
+            interfaces: vec!["test-if0".to_string()],
+            local_subnet: false,
+        }
+    }
+
+    /// Pretends the interfaces were just read and carry `nets`
+    fn cache(access: &AccessControl, config: &AccessConfig, nets: &[&str], read_at: Instant) {
+        *access.0.lock().unwrap() = Some(Subnets {
+            read_at,
+            config: config.clone(),
+            nets: nets.iter().map(|n| n.parse().unwrap()).collect(),
+        });
+    }
+
+    #[test]
+    fn loopback_is_always_allowed() {
+        let access = AccessControl::new();
+        let config = config(&["192.168.1.0/24"]);
+        let network = NetworkConfig::default();
+        for client in ["127.0.0.1", "127.8.9.1", "::1"] {
+            assert!(access.allows(ip(client), &config, &network), "{}", client);
+        }
+    }
+
+    #[test]
+    fn allow_list() {
+        let access = AccessControl::new();
+        let config = AccessConfig {
+            interfaces: Vec::new(),
+            ..config(&["192.168.1.0/24", "10.8.0.5", "fd00::/64"])
+        };
+        let network = NetworkConfig::default();
+        for (client, allowed) in [
+            ("192.168.1.1", true),
+            ("192.168.1.254", true),
+            ("192.168.2.1", false),
+            ("10.8.0.5", true),
+            ("10.8.0.6", false),
+            ("fd00::20", true),
+            ("fd00:0:0:1::20", false),
+        ] {
+            assert_eq!(access.allows(ip(client), &config, &network), allowed, "{}", client);
+        }
+        // nothing else to match, so the interfaces are never read
+        assert!(access.0.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn interface_subnets() {
+        let access = AccessControl::new();
+        let config = config(&[]);
+        let network = NetworkConfig::default();
+        cache(&access, &config, &["10.1.0.0/16", "fd00:1::/64"], Instant::now());
+        assert!(access.allows(ip("10.1.200.3"), &config, &network));
+        assert!(access.allows(ip("fd00:1::7"), &config, &network));
+        assert!(!access.allows(ip("10.2.0.1"), &config, &network));
+    }
+
+    #[test]
+    fn subnets_are_read_again_once_stale() {
+        let access = AccessControl::new();
+        let config = config(&[]);
+        let network = NetworkConfig::default();
+        let stale = Instant::now() - SUBNETS_TTL - Duration::from_secs(1);
+        cache(&access, &config, &["10.1.0.0/16"], stale);
+        // test-if0 does not exist, so the fresh read finds no subnet
+        assert!(!access.allows(ip("10.1.200.3"), &config, &network));
+        assert!(access.0.lock().unwrap().as_ref().unwrap().nets.is_empty());
+    }
+
+    #[test]
+    fn subnets_are_read_again_when_the_settings_change() {
+        let access = AccessControl::new();
+        let network = NetworkConfig::default();
+        cache(&access, &config(&[]), &["10.1.0.0/16"], Instant::now());
+        let changed = AccessConfig {
+            interfaces: vec!["test-if1".to_string()],
+            ..config(&[])
+        };
+        assert!(!access.allows(ip("10.1.200.3"), &changed, &network));
+    }
+}
@@ -0,0 +1,512 @@
+//! This module moves a server directory between machines as a single tar archive
+//!
+//! An archive holds a manifest, a snapshot of the database taken with SQLite's
+//! online backup API, the config, the static files and the uploaded files.
+//! An incremental archive leaves out the uploaded files its base archive already has,
+//! so it can only be restored on top of a directory the base was restored to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, MAIN_DB};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{CONFIG_FNAME, Config},
+    fm::FileManager,
+    utils,
+};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+/// Archive layout version, bumped on incompatible changes
+const FORMAT: u32 = 1;
+const DB_ENTRY: &str = "localshare.db";
+const UPLOADS_ENTRY: &str = "uploads";
+const STATIC_ENTRY: &str = "static";
+/// Where an incremental restore is unpacked before it is applied
+const STAGING_DIR: &str = ".restore";
+/// Where an incremental restore keeps the replaced files until it succeeds
+const PREVIOUS_DIR: &str = ".previous";
+
+/// Describes the content of an archive, always its first entry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub id: Uuid,
+    /// localshare version that wrote the archive
+    pub version: String,
+    pub created_at: DateTime<Utc>,
+    /// Archive this one is incremental to
+    pub base: Option<Uuid>,
+    /// Every uploaded file referenced by the database snapshot
+    pub blobs: Vec<Blob>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Blob {
+    pub uuid: Uuid,
+    pub sha256: String,
+    /// false when the file was left out because the base archive has it
+    pub included: bool,
+}
+
+impl Manifest {
+    pub fn is_incremental(&self) -> bool {
+        self.base.is_some()
+    }
+}
+
+/// Reads the manifest of an archive without unpacking it
+pub fn read_manifest(archive: &Path) -> anyhow::Result<Manifest> {
+    let file = File::open(archive).context(format!("Could not open {}", archive.display()))?;
+    let mut tar = tar::Archive::new(file);
+    let mut entries = tar.entries()?;
+    let mut first = entries.next().context("Archive is empty")??;
+    manifest_from_entry(&mut first)
+}
+
+fn manifest_from_entry<R: Read>(entry: &mut tar::Entry<R>) -> anyhow::Result<Manifest> {
+    if entry.path()?.as_ref() != Path::new(MANIFEST_NAME) {
+        anyhow::bail!("Not a localshare archive: {} is missing", MANIFEST_NAME);
+    }
+    let manifest: Manifest = rocket::serde::json::serde_json::from_reader(entry)
+        .context(format!("Invalid {}", MANIFEST_NAME))?;
+    if manifest.format != FORMAT {
+        anyhow::bail!(
+            "Unsupported archive format {}, this localshare reads format {}",
+            manifest.format,
+            FORMAT
+        );
+    }
+    Ok(manifest)
+}
+
+/// Writes `workdir` to `archive`. With `base`, files already in the base archive are left out.
+/// Safe to run while the server is running: the database is snapshotted first,
+/// and only the files referenced by the snapshot are archived.
+pub fn export(
+    workdir: &Path,
+    conf: &Config,
+    archive: &Path,
+    base: Option<&Path>,
+) -> anyhow::Result<Manifest> {
+    let base = base.map(read_manifest).transpose()?;
+    let in_base: HashMap<Uuid, String> = base
+        .iter()
+        .flat_map(|m| &m.blobs)
+        .map(|b| (b.uuid, b.sha256.clone()))
+        .collect();
+
+    let snapshot_name = format!(".{}.export", DB_ENTRY);
+    let snapshot = workdir.join(&snapshot_name);
+    let _cleanup = RemoveOnDrop(snapshot.clone());
+    Connection::open(workdir.join(&conf.path.db))?
+        .backup(MAIN_DB, &snapshot, None)
+        .context("Database snapshot failed")?;
+
+    let mut snapshot_conf = conf.clone();
+    snapshot_conf.path.db = snapshot_name;
+    let records = FileManager::new(workdir, snapshot_conf)?.get_all_records()?;
+
+    let uploads_dir = workdir.join(&conf.path.uploads);
+    let mut blobs = Vec::with_capacity(records.len());
+    for record in &records {
+        let path = uploads_dir.join(record.uuid.to_string());
+        let sha256 = utils::sha256_file(&path).context(format!(
+            "Could not read the file of {} ({}), run 'localshare fsck' first",
+            record.uuid, record.name
+        ))?;
+        let included = in_base.get(&record.uuid) != Some(&sha256);
+        blobs.push(Blob {
+            uuid: record.uuid,
+            sha256,
+            included,
+        });
+    }
+    let manifest = Manifest {
+        format: FORMAT,
+        id: Uuid::new_v4(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: Utc::now(),
+        base: base.map(|m| m.id),
+        blobs,
+    };
+
+    let file = File::create(archive).context(format!("Could not create {}", archive.display()))?;
+    let mut tar = tar::Builder::new(file);
+    let json = rocket::serde::json::serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at.timestamp().max(0) as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, MANIFEST_NAME, json.as_slice())?;
+    tar.append_path_with_name(workdir.join(CONFIG_FNAME), CONFIG_FNAME)?;
+    tar.append_path_with_name(&snapshot, DB_ENTRY)?;
+
+    let static_dir = workdir.join(&conf.path.r#static);
+    if static_dir.is_dir() {
+        for entry in std::fs::read_dir(&static_dir)? {
+            let path = entry?.path();
+            if path.is_file()
+                && let Some(name) = path.file_name()
+            {
+                tar.append_path_with_name(&path, Path::new(STATIC_ENTRY).join(name))?;
+            }
+        }
+    }
+    for blob in manifest.blobs.iter().filter(|b| b.included) {
+        let name = blob.uuid.to_string();
+        tar.append_path_with_name(
+            uploads_dir.join(&name),
+            Path::new(UPLOADS_ENTRY).join(&name),
+        )
+        .context(format!(
+            "Could not archive {}, was it deleted during the export?",
+            name
+        ))?;
+    }
+    tar.into_inner()?.sync_all()?;
+    Ok(manifest)
+}
+
+/// Unpacks `archive` into `workdir` after checking every file against the manifest.
+/// A full archive needs a directory that does not exist yet, an incremental one
+/// the directory its base was restored to. The server must be stopped.
+pub fn restore(archive: &Path, workdir: &Path) -> anyhow::Result<Manifest> {
+    let manifest = read_manifest(archive)?;
+    let staging = if manifest.is_incremental() {
+        if !workdir.join(CONFIG_FNAME).exists() {
+            anyhow::bail!(
+                "'{}' is an incremental archive, restore its base into '{}' first",
+                archive.display(),
+                workdir.display()
+            );
+        }
+        workdir.join(STAGING_DIR)
+    } else {
+        if workdir.exists() {
+            anyhow::bail!(
+                "Directory '{}' already exists. Remove it first or choose a different path.",
+                workdir.display()
+            );
+        }
+        workdir.to_path_buf()
+    };
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(staging.join(UPLOADS_ENTRY))?;
+    std::fs::create_dir_all(staging.join(STATIC_ENTRY))?;
+
+    let result = unpack(archive, &staging)
+        .and_then(|()| verify(&manifest, workdir, &staging))
+        .and_then(|conf| {
+            if manifest.is_incremental() {
+                apply_incremental(workdir, &staging, &conf, &manifest)
+            } else {
+                apply_full(workdir, &conf)
+            }
+        });
+    // files a failed swap could not put back are left for the user
+    let previous = staging.join(PREVIOUS_DIR);
+    if result.is_err() && std::fs::read_dir(&previous).is_ok_and(|mut d| d.next().is_some()) {
+        return result
+            .context(format!(
+                "Restore failed and could not put every file back, the previous files are in '{}'",
+                previous.display()
+            ))
+            .map(|()| manifest);
+    }
+    if manifest.is_incremental() || result.is_err() {
+        std::fs::remove_dir_all(&staging).ok();
+    }
+    result.map(|()| manifest)
+}
+
+/// Extracts the known entries, refusing anything else.
+/// Only regular files are extracted, a link could point anywhere outside the server directory,
+/// and every path only once, so a later entry can not replace a verified one.
+fn unpack(archive: &Path, staging: &Path) -> anyhow::Result<()> {
+    let mut tar = tar::Archive::new(File::open(archive)?);
+    let mut seen = HashSet::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            anyhow::bail!(
+                "Unexpected entry in archive: {} is not a regular file",
+                path.display()
+            );
+        }
+        if !seen.insert(path.clone()) {
+            anyhow::bail!("Duplicate entry in archive: {}", path.display());
+        }
+        let target = match path.to_str() {
+            Some(MANIFEST_NAME) => continue,
+            Some(CONFIG_FNAME) | Some(DB_ENTRY) => staging.join(&path),
+            _ => {
+                let (dir, name) = (path.parent(), path.file_name());
+                match (dir.and_then(|d| d.to_str()), name.and_then(|n| n.to_str())) {
+                    (Some(UPLOADS_ENTRY), Some(name)) if name.parse::<Uuid>().is_ok() => {
+                        staging.join(&path)
+                    }
+                    (Some(STATIC_ENTRY), Some(name)) if !name.starts_with('.') => {
+                        staging.join(&path)
+                    }
+                    _ => anyhow::bail!("Unexpected entry in archive: {}", path.display()),
+                }
+            }
+        };
+        entry
+            .unpack(&target)
+            .context(format!("Could not extract {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Checks the unpacked files against the manifest and returns the archived config
+fn verify(manifest: &Manifest, workdir: &Path, staging: &Path) -> anyhow::Result<Config> {
+    let content = std::fs::read_to_string(staging.join(CONFIG_FNAME))
+        .context(format!("Archive has no {}", CONFIG_FNAME))?;
+    let conf: Config = toml::from_str(&content).context(format!("Invalid {}", CONFIG_FNAME))?;
+    conf.validate()?;
+    check_relative("path.db", &conf.path.db)?;
+    check_relative("path.uploads", &conf.path.uploads)?;
+    check_relative("path.static", &conf.path.r#static)?;
+    for (key, path) in [
+        ("share.directory", &conf.share.directory),
+        ("network.unix_socket", &conf.network.unix_socket),
+        ("encryption.key_file", &conf.encryption.key_file),
+    ] {
+        if let Some(path) = path {
+            check_relative(key, path)?;
+        }
+    }
+
+    // files left out of an incremental archive must already be in place
+    let existing_uploads = workdir.join(&conf.path.uploads);
+    for blob in &manifest.blobs {
+        let name = blob.uuid.to_string();
+        let path = if blob.included {
+            staging.join(UPLOADS_ENTRY).join(&name)
+        } else {
+            existing_uploads.join(&name)
+        };
+        let sha256 = utils::sha256_file(&path).context(if blob.included {
+            format!("Archive is missing the file {}", name)
+        } else {
+            format!(
+                "File {} of the base archive is missing from '{}'",
+                name,
+                workdir.display()
+            )
+        })?;
+        if sha256 != blob.sha256 {
+            anyhow::bail!("Checksum mismatch for {}: archive is damaged", name);
+        }
+    }
+
+    let snapshot = staging.join(DB_ENTRY);
+    if !snapshot.exists() {
+        anyhow::bail!("Archive has no database");
+    }
+    let mut snapshot_conf = conf.clone();
+    snapshot_conf.path.db = DB_ENTRY.to_string();
+    let listed: HashSet<Uuid> = manifest.blobs.iter().map(|b| b.uuid).collect();
+    for record in FileManager::new(staging, snapshot_conf)?.get_all_records()? {
+        if !listed.contains(&record.uuid) {
+            anyhow::bail!(
+                "Database references {} which is not in the archive",
+                record.uuid
+            );
+        }
+    }
+    Ok(conf)
+}
+
+/// Moves a fully unpacked archive to the locations the config asks for
+fn apply_full(workdir: &Path, conf: &Config) -> anyhow::Result<()> {
+    move_path(&workdir.join(DB_ENTRY), &workdir.join(&conf.path.db))?;
+    move_path(
+        &workdir.join(UPLOADS_ENTRY),
+        &workdir.join(&conf.path.uploads),
+    )?;
+    move_path(
+        &workdir.join(STATIC_ENTRY),
+        &workdir.join(&conf.path.r#static),
+    )?;
+    Ok(())
+}
+
+/// Builds the complete result in `staging`, then swaps it in.
+/// The live files are only moved aside, so a failed step puts them back.
+fn apply_incremental(
+    workdir: &Path,
+    staging: &Path,
+    conf: &Config,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
+    let uploads_dir = workdir.join(&conf.path.uploads);
+    let static_dir = workdir.join(&conf.path.r#static);
+    let staged_uploads = staging.join(UPLOADS_ENTRY);
+    let staged_static = staging.join(STATIC_ENTRY);
+
+    // files left out of the archive come from the base, files deleted since are not carried over
+    for blob in manifest.blobs.iter().filter(|b| !b.included) {
+        let name = blob.uuid.to_string();
+        link_or_copy(&uploads_dir.join(&name), &staged_uploads.join(&name))?;
+    }
+    // static files the archive does not replace are kept
+    if static_dir.is_dir() {
+        for entry in std::fs::read_dir(&static_dir)? {
+            let entry = entry?;
+            let target = staged_static.join(entry.file_name());
+            if entry.file_type()?.is_file() && !target.exists() {
+                link_or_copy(&entry.path(), &target)?;
+            }
+        }
+    }
+
+    let db = workdir.join(&conf.path.db);
+    let mut swap = Swap::new(staging.join(PREVIOUS_DIR))?;
+    swap.replace(Some(&staged_uploads), &uploads_dir)?;
+    swap.replace(Some(&staged_static), &static_dir)?;
+    // a journal left next to the old database would be replayed into the new one
+    for suffix in ["-wal", "-shm"] {
+        let mut journal = db.clone().into_os_string();
+        journal.push(suffix);
+        swap.replace(None, Path::new(&journal))?;
+    }
+    swap.replace(Some(&staging.join(DB_ENTRY)), &db)?;
+    swap.replace(Some(&staging.join(CONFIG_FNAME)), &workdir.join(CONFIG_FNAME))?;
+    swap.commit();
+    Ok(())
+}
+
+/// Rejects paths that could point outside the server directory
+fn check_relative(key: &str, path: &str) -> anyhow::Result<()> {
+    let path = Path::new(path);
+    if path.as_os_str().is_empty()
+        || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!(
+            "Archived {} sets {} to '{}', only relative paths inside the server directory can be restored",
+            CONFIG_FNAME,
+            key,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::hard_link(from, to)
+        .or_else(|_| std::fs::copy(from, to).map(|_| ()))
+        .context(format!("Could not copy {} to {}", from.display(), to.display()))
+}
+
+fn move_path(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if from == to {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to).context(format!(
+        "Could not move {} to {}",
+        from.display(),
+        to.display()
+    ))
+}
+
+/// Removes a temporary file however the function returns
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Replaces live paths with staged ones.
+/// Unless committed, every replaced path is put back when dropped.
+struct Swap {
+    previous: PathBuf,
+    steps: Vec<SwapStep>,
+    committed: bool,
+}
+
+struct SwapStep {
+    live: PathBuf,
+    staged: Option<PathBuf>,
+    previous: Option<PathBuf>,
+}
+
+impl Swap {
+    fn new(previous: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&previous)?;
+        Ok(Self {
+            previous,
+            steps: Vec::new(),
+            committed: false,
+        })
+    }
+
+    /// Moves `live` aside, then `staged` into its place. Without `staged`, `live` is only removed.
+    fn replace(&mut self, staged: Option<&Path>, live: &Path) -> anyhow::Result<()> {
+        let previous = if live.symlink_metadata().is_ok() {
+            let previous = self.previous.join(self.steps.len().to_string());
+            move_path(live, &previous)?;
+            Some(previous)
+        } else {
+            None
+        };
+        self.steps.push(SwapStep {
+            live: live.to_path_buf(),
+            staged: None,
+            previous,
+        });
+        if let Some(staged) = staged {
+            move_path(staged, live)?;
+            if let Some(step) = self.steps.last_mut() {
+                step.staged = Some(staged.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for Swap {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for step in self.steps.iter().rev() {
+            if let Some(staged) = &step.staged {
+                std::fs::rename(&step.live, staged).ok();
+            }
+            if let Some(previous) = &step.previous
+                && let Err(e) = std::fs::rename(previous, &step.live)
+            {
+                log::error!(
+                    "restore: could not put {} back from {}: {}",
+                    step.live.display(),
+                    previous.display(),
+                    e
+                );
+            }
+        }
+    }
+}
@@ -11,6 +11,8 @@ pub enum StaticFile {
     Index,
     Upload,
     Login,
+    Share,
+    E2e,
 }
 
 
@@ -22,6 +24,8 @@ impl From<StaticFile> for PathBuf {
             // StaticFile::NotFound => PathBuf::from("not_found.html"),
             StaticFile::Upload => PathBuf::from("upload.html"),
             StaticFile::Login => PathBuf::from("login.html"),
+            StaticFile::Share => PathBuf::from("share.html"),
+            StaticFile::E2e => PathBuf::from("e2e.js"),
         }
     }
 }
@@ -29,13 +33,22 @@ impl From<StaticFile> for PathBuf {
 /// Used for extracting embedded static files.
 pub struct Assets(Vec<PathBuf>);
 
+impl Default for Assets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assets {
     pub fn new() -> Self {
-        let mut assets : Vec<PathBuf> = Vec::new();
-        assets.push(StaticFile::Index.into());
-        // assets.push(StaticFile::NotFound.into());
-        assets.push(StaticFile::Upload.into());
-        assets.push(StaticFile::Login.into());
+        let assets : Vec<PathBuf> = vec![
+            StaticFile::Index.into(),
+            // StaticFile::NotFound.into(),
+            StaticFile::Upload.into(),
+            StaticFile::Login.into(),
+            StaticFile::Share.into(),
+            StaticFile::E2e.into(),
+        ];
         Self(assets)
     }
 
@@ -86,7 +99,7 @@ impl Assets {
     pub async fn extract_to_dir<P : AsRef<Path>>(&self ,dir : P) -> io::Result<()>{
         for asset in &self.0 {
             let embedded_file = StaticAssets::get(asset.to_str().unwrap()).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "embedded file not found"))?;
-            let mut file = tokio::fs::File::create(dir.as_ref().join(&asset)).await?;
+            let mut file = tokio::fs::File::create(dir.as_ref().join(asset)).await?;
             file.write_all(&embedded_file.data).await?;
         }
         Ok(())
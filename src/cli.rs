@@ -1,4 +1,6 @@
-use clap::{Arg, Command, command};
+use clap::{Arg, ArgMatches, Command, command};
+
+use crate::config::{BindAddr, Overrides};
 
 pub fn get_command() -> Command {
     command!()
@@ -51,21 +53,232 @@ pub fn get_command() -> Command {
                      • Register itself on the local network via mDNS so nearby devices \
                        can discover it automatically\n  \
                      • Print a QR code to the terminal for quick mobile access\n\n\
+                     Settings from LocalShare.toml can be overridden with LOCALSHARE_* \
+                     environment variables and with the flags below, flags winning.\n\n\
                      The directory must have been initialised first with:\n  \
                      localshare new <workdir>",
                 )
+                .arg(workdir_arg())
+                .args(override_args()),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration of a LocalShare server directory")
+                .subcommand(
+                    Command::new("show")
+                        .about("Print the effective configuration")
+                        .long_about(
+                            "Prints the configuration the server would run with: \
+                             LocalShare.toml with defaults filled in for missing keys \
+                             and LOCALSHARE_* environment overrides applied.",
+                        )
+                        .arg(workdir_arg()),
+                )
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate the configuration")
+                        .long_about(
+                            "Validates LocalShare.toml and LOCALSHARE_* environment \
+                             overrides. Every problem is reported with the key it \
+                             belongs to, and the command fails if any is found.",
+                        )
+                        .arg(workdir_arg()),
+                )
+                .subcommand_required(true),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Import existing files into a LocalShare server directory")
+                .long_about(
+                    "Walks the given directory and adds every file in it, including \
+                     subdirectories, to the server. File names and modification times \
+                     are kept; symbolic links are skipped.\n\n\
+                     Running the import again is safe: files imported before are \
+                     skipped, and files whose content changed are updated in place.",
+                )
+                .arg(workdir_arg())
                 .arg(
-                    Arg::new("workdir")
+                    Arg::new("path")
                         .value_parser(clap::builder::PathBufValueParser::new())
-                        .help("Path to an initialised LocalShare server directory")
+                        .help("Directory to import")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("author")
+                        .long("author")
+                        .default_value("localshare import")
+                        .help("Author recorded for imported files"),
+                )
+                .arg(
+                    Arg::new("link")
+                        .long("link")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Hard-link files instead of copying them")
                         .long_help(
-                            "Path to a directory that was previously set up with \
-                             'localshare new'. The directory must contain a valid \
-                             LocalShare.toml configuration file.",
-                        )
+                            "Hard-link files into the uploads directory instead of \
+                             copying them, saving disk space. Files are copied when \
+                             linking is not possible, e.g. across filesystems.\n\n\
+                             Linked files share their content with the originals, so \
+                             editing an original also changes the shared file.",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Write a server directory to a single archive")
+                .long_about(
+                    "Writes the configuration, the database and every shared file to a \
+                     tar archive that 'localshare restore' can unpack on another machine.\n\n\
+                     The database is copied with SQLite's online backup API, so the \
+                     server may keep running during the export.\n\n\
+                     With --base the archive is incremental: files that are unchanged \
+                     since the base archive are left out. An incremental archive is \
+                     restored on top of a directory its base was restored to.",
+                )
+                .arg(workdir_arg())
+                .arg(
+                    Arg::new("archive")
+                        .value_parser(clap::builder::PathBufValueParser::new())
+                        .help("Path of the archive to write")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("base")
+                        .long("base")
+                        .value_parser(clap::builder::PathBufValueParser::new())
+                        .help("Earlier archive to make an incremental archive against"),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Recreate a server directory from an archive")
+                .long_about(
+                    "Unpacks an archive written by 'localshare export'. Every file is \
+                     checked against the checksums in the archive before anything is \
+                     put in place.\n\n\
+                     A full archive is restored to a new directory, which must not \
+                     exist yet. An incremental archive is restored on top of the \
+                     directory its base archive was restored to. Stop the server \
+                     before restoring into its directory.",
+                )
+                .arg(
+                    Arg::new("archive")
+                        .value_parser(clap::builder::PathBufValueParser::new())
+                        .help("Archive written by 'localshare export'")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("workdir")
+                        .value_parser(clap::builder::PathBufValueParser::new())
+                        .help("Server directory to restore to")
                         .required(true),
                 ),
         )
+        .subcommand(
+            Command::new("fsck")
+                .about("Check that uploaded files and the database agree")
+                .long_about(
+                    "Compares the uploads directory with the file records and reports \
+                     files without a record, records without a file and files whose \
+                     content no longer matches the stored SHA-256 checksum.\n\n\
+                     Run it while the server is stopped, otherwise uploads in progress \
+                     are reported as orphan files.\n\n\
+                     With --repair the problems are fixed:\n  \
+                     • delete      remove orphan files, dangling records and corrupted files\n  \
+                     • quarantine  move orphan and corrupted files to <workdir>/quarantine/\n  \
+                     • reimport    create records for orphan files and accept the current \
+                     content of corrupted files\n\n\
+                     Dangling records are removed and missing checksums are stored in \
+                     every mode.",
+                )
+                .arg(workdir_arg())
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .value_parser(["delete", "reimport", "quarantine"])
+                        .help("Fix the problems found"),
+                ),
+        )
         .subcommand_required(true)
         .propagate_version(true)
-}
\ No newline at end of file
+}
+
+fn workdir_arg() -> Arg {
+    Arg::new("workdir")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help("Path to an initialised LocalShare server directory")
+        .long_help(
+            "Path to a directory that was previously set up with \
+             'localshare new'. The directory must contain a valid \
+             LocalShare.toml configuration file.",
+        )
+        .required(true)
+}
+
+fn override_args() -> Vec<Arg> {
+    vec![
+        Arg::new("port")
+            .long("port")
+            .value_parser(clap::value_parser!(u16).range(1..))
+            .help("Port to listen on [env: LOCALSHARE_PORT]"),
+        Arg::new("bind")
+            .long("bind")
+            .action(clap::ArgAction::Append)
+            .value_parser(clap::builder::ValueParser::new(|s: &str| {
+                s.parse::<BindAddr>()
+            }))
+            .help("Address to listen on, \"ip\" or \"ip:port\", repeatable [env: LOCALSHARE_BIND]"),
+        Arg::new("unix-socket")
+            .long("unix-socket")
+            .help("Also listen on this unix socket [env: LOCALSHARE_UNIX_SOCKET]"),
+        Arg::new("instance-name")
+            .long("instance-name")
+            .help("mDNS instance name [env: LOCALSHARE_INSTANCE_NAME]"),
+        Arg::new("auth")
+            .long("auth")
+            .action(clap::ArgAction::SetTrue)
+            .overrides_with("no-auth")
+            .help("Require a password for admin actions [env: LOCALSHARE_AUTH]"),
+        Arg::new("no-auth")
+            .long("no-auth")
+            .action(clap::ArgAction::SetTrue)
+            .help("Do not require a password for admin actions"),
+        Arg::new("key-file")
+            .long("key-file")
+            .help("Encrypt uploads with the key in this file [env: LOCALSHARE_KEY_FILE]"),
+        Arg::new("debug")
+            .long("debug")
+            .action(clap::ArgAction::SetTrue)
+            .overrides_with("release")
+            .help("Run with the debug profile [env: LOCALSHARE_DEBUG]"),
+        Arg::new("release")
+            .long("release")
+            .action(clap::ArgAction::SetTrue)
+            .help("Run with the release profile"),
+    ]
+}
+
+/// Collects the config overrides given as `run` flags
+pub fn overrides(matches: &ArgMatches) -> Overrides {
+    let flag = |on: &str, off: &str| {
+        if matches.get_flag(on) {
+            Some(true)
+        } else if matches.get_flag(off) {
+            Some(false)
+        } else {
+            None
+        }
+    };
+    Overrides {
+        port: matches.get_one::<u16>("port").copied(),
+        debug: flag("debug", "release"),
+        auth: flag("auth", "no-auth"),
+        bind: matches
+            .get_many::<BindAddr>("bind")
+            .map(|addrs| addrs.copied().collect()),
+        unix_socket: matches.get_one::<String>("unix-socket").cloned(),
+        instance_name: matches.get_one::<String>("instance-name").cloned(),
+        ipv6: None,
+        key_file: matches.get_one::<String>("key-file").cloned(),
+    }
+}
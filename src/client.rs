@@ -0,0 +1,114 @@
+//! This module tells which client a request comes from
+//!
+//! Requests reaching the server over loopback come from a reverse proxy or
+//! the unix socket, so the `X-Real-IP` header they carry names the client.
+//! Anyone else could forge the header, their own address is used instead.
+//! The header is trusted as it is: local processes that can reach the unix
+//! socket or a loopback port can claim any address to the access and rate
+//! limits, so a proxy must overwrite the header rather than pass it on.
+
+use std::net::IpAddr;
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::{
+    r#gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+
+/// Header a reverse proxy sets to the address of the client
+const REAL_IP_HEADER: &str = "X-Real-IP";
+
+/// Address of the client that sent `request`, `None` when it is unknown
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let remote = request.remote()?.ip().to_canonical();
+    if remote.is_loopback()
+        && let Some(real_ip) = request
+            .headers()
+            .get_one(REAL_IP_HEADER)
+            .and_then(|h| h.trim().parse::<IpAddr>().ok())
+    {
+        return Some(real_ip.to_canonical());
+    }
+    Some(remote)
+}
+
+/// The address of the client, see [`client_ip`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match client_ip(request) {
+            Some(ip) => Outcome::Success(Self(ip)),
+            None => Outcome::Forward(rocket::http::Status::InternalServerError),
+        }
+    }
+}
+
+/// Taken from the connection, not documented as a parameter
+impl<'r> OpenApiFromRequest<'r> for ClientIp {
+    fn from_request_input(
+        _generator: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rocket::{http::Header, local::blocking::Client};
+
+    use super::*;
+
+    #[rocket::get("/")]
+    fn client(client: ClientIp) -> String {
+        client.0.to_string()
+    }
+
+    fn seen(remote: &str, real_ip: Option<&str>) -> String {
+        let rocket = rocket::build().mount("/", rocket::routes![client]);
+        let client = Client::untracked(rocket).unwrap();
+        let mut request = client.get("/").remote(remote.parse::<SocketAddr>().unwrap());
+        if let Some(real_ip) = real_ip {
+            request = request.header(Header::new(REAL_IP_HEADER, real_ip.to_string()));
+        }
+        request.dispatch().into_string().unwrap()
+    }
+
+    #[test]
+    fn remote_address() {
+        assert_eq!(seen("192.168.1.5:50000", None), "192.168.1.5");
+        assert_eq!(seen("[fd00::5]:50000", None), "fd00::5");
+        // IPv4 clients of a dual stack socket
+        assert_eq!(seen("[::ffff:192.168.1.5]:50000", None), "192.168.1.5");
+    }
+
+    #[test]
+    fn proxies_on_loopback_name_the_client() {
+        assert_eq!(seen("127.0.0.1:50000", Some("192.168.1.5")), "192.168.1.5");
+        assert_eq!(seen("[::1]:50000", Some(" fd00::5 ")), "fd00::5");
+        assert_eq!(seen("127.0.0.1:50000", Some("::ffff:10.0.0.1")), "10.0.0.1");
+        assert_eq!(seen("[::ffff:127.0.0.1]:50000", Some("192.168.1.5")), "192.168.1.5");
+        assert_eq!(seen("127.0.0.1:50000", None), "127.0.0.1");
+    }
+
+    #[test]
+    fn other_clients_can_not_forge_the_header() {
+        assert_eq!(seen("192.168.1.5:50000", Some("127.0.0.1")), "192.168.1.5");
+        assert_eq!(seen("[fd00::5]:50000", Some("10.0.0.1")), "fd00::5");
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        assert_eq!(seen("127.0.0.1:50000", Some("192.168.1.5, 10.0.0.1")), "127.0.0.1");
+        assert_eq!(seen("127.0.0.1:50000", Some("unknown")), "127.0.0.1");
+        assert_eq!(seen("127.0.0.1:50000", Some("")), "127.0.0.1");
+    }
+}
@@ -0,0 +1,147 @@
+//! This module compresses stored files, see `storage.compression`
+//!
+//! Uploads whose type is worth it are compressed with zstd while they are
+//! written, and their record keeps the encoding next to the original and the
+//! stored size. Downloads are decompressed on the fly, or sent as stored with
+//! `Content-Encoding: zstd` to clients that accept it.
+
+use std::{io, ops::Range};
+
+use async_compression::{
+    Level,
+    tokio::{bufread::ZstdDecoder, write::ZstdEncoder},
+};
+use rocket::{
+    http::ContentType,
+    request::{FromRequest, Outcome, Request},
+};
+use rocket_okapi::{
+    r#gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use tokio::io::{AsyncReadExt, AsyncWrite, BufReader};
+
+use crate::{config::CompressionConfig, storage::BlobReader};
+
+/// Encoding recorded for zstd compressed files, also the `Content-Encoding` token
+pub const ZSTD: &str = "zstd";
+
+/// Extensions Rocket has no type for, mostly plain text
+const EXTRA_TYPES: &[(&str, &str)] = &[
+    ("log", "text/plain"),
+    ("conf", "text/plain"),
+    ("cfg", "text/plain"),
+    ("ini", "text/plain"),
+    ("toml", "text/plain"),
+    ("sql", "text/plain"),
+    ("md", "text/markdown"),
+    ("yaml", "text/yaml"),
+    ("yml", "text/yaml"),
+    ("tsv", "text/tab-separated-values"),
+    ("ndjson", "application/x-ndjson"),
+    ("jsonl", "application/x-ndjson"),
+];
+
+/// MIME type of a file, guessed from the extension of its name
+pub fn media_type(filename: &str) -> Option<String> {
+    let extension = filename.rsplit_once('.')?.1.to_ascii_lowercase();
+    if let Some((_, media_type)) = EXTRA_TYPES.iter().find(|(e, _)| *e == extension) {
+        return Some(media_type.to_string());
+    }
+    ContentType::from_extension(&extension).map(|t| format!("{}/{}", t.top(), t.sub()))
+}
+
+/// Level to compress a file named `filename` at, `None` to store it as is
+pub fn level_for(config: &CompressionConfig, filename: &str) -> Option<i32> {
+    if !config.enabled {
+        return None;
+    }
+    let media_type = media_type(filename)?;
+    let (top, _) = media_type.split_once('/')?;
+    let matches = config.types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(pattern_top) => pattern_top.eq_ignore_ascii_case(top),
+        None => pattern.eq_ignore_ascii_case(&media_type),
+    });
+    matches.then_some(config.level)
+}
+
+/// Writes to `inner`, compressing at `level` if set.
+/// Must be shut down for the compressed stream to be complete.
+pub fn writer(
+    inner: Box<dyn AsyncWrite + Send + Unpin>,
+    level: Option<i32>,
+) -> Box<dyn AsyncWrite + Send + Unpin> {
+    match level {
+        Some(level) => Box::new(ZstdEncoder::with_quality(inner, Level::Precise(level))),
+        None => inner,
+    }
+}
+
+/// Decompresses a stored blob, only yielding `range` of the content if given.
+/// The stream can only be decoded from its start, so a range late in a large
+/// file takes a while to begin.
+pub async fn decode(blob: BlobReader, range: Option<Range<u64>>) -> io::Result<BlobReader> {
+    let mut decoder = ZstdDecoder::new(BufReader::new(blob));
+    let Some(range) = range else {
+        return Ok(Box::pin(decoder));
+    };
+    let skipped = tokio::io::copy(&mut (&mut decoder).take(range.start), &mut tokio::io::sink()).await?;
+    if skipped < range.start {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "compressed content is shorter than recorded",
+        ));
+    }
+    Ok(Box::pin(decoder.take(range.end - range.start)))
+}
+
+/// Whether the client takes zstd encoded responses, from `Accept-Encoding`
+#[derive(Debug, Default)]
+pub struct AcceptEncoding {
+    zstd: bool,
+}
+
+impl AcceptEncoding {
+    pub fn zstd(&self) -> bool {
+        self.zstd
+    }
+}
+
+/// Whether `header` lists `coding` without `q=0`. Wildcards are not honored.
+fn accepts(header: &str, coding: &str) -> bool {
+    header.split(',').any(|member| {
+        let mut params = member.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        name.eq_ignore_ascii_case(coding) && quality > 0.0
+    })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptEncoding {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self {
+            zstd: request
+                .headers()
+                .get("Accept-Encoding")
+                .any(|header| accepts(header, ZSTD)),
+        })
+    }
+}
+
+/// Content negotiation, not documented as a parameter
+impl<'r> OpenApiFromRequest<'r> for AcceptEncoding {
+    fn from_request_input(
+        _generator: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
@@ -1,56 +1,695 @@
-use serde::{Deserialize, Serialize};
+use std::{fmt, net::{IpAddr, SocketAddr}, str::FromStr};
+
+use ipnet::IpNet;
+use rocket::data::{ByteUnit, ToByteUnit};
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub const DB_NAME: &str = "localshare.db";
 pub const UPLOAD_DIR: &str = "uploads";
-pub const DEFAULT_PORT: &str = "8080";
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_GRACE_PERIOD: u32 = 30;
+pub const DEFAULT_RESCAN_INTERVAL: u32 = 30;
+pub const DEFAULT_S3_REGION: &str = "us-east-1";
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// Highest accepted zstd level. Uploads are compressed on the threads serving
+/// requests, higher levels would keep them busy long enough to stall everyone else.
+pub const MAX_COMPRESSION_LEVEL: i32 = 9;
+pub const DEFAULT_BIND: BindAddr = BindAddr::Ip(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
 pub const STATIC_DIR: &str = "static";
 pub const CONFIG_FNAME: &str = "LocalShare.toml";
+pub const QUARANTINE_DIR: &str = "quarantine";
+/// Inside the uploads directory, so finished uploads are moved within one filesystem
+pub const STAGING_DIR: &str = ".staging";
 pub const QR_ACCESS_FNAME : &str = "qr_access.png";
+pub const QR_ACCESS_SVG_FNAME : &str = "qr_access.svg";
 pub const SESSION_COOKIE_NAME : &str = "session_id";
+pub const MDNS_SERVICE_TYPE: &str = "_localshare._tcp.local.";
+pub const API_PATH: &str = "/api";
+/// Current version of the JSON API, the unversioned paths stay as aliases
+pub const API_V1_PATH: &str = "/api/v1";
 
 
+/// Missing keys fall back to their defaults.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Config {
     pub version: String,
     pub app: AppConfig,
     pub path: PathConfig,
+    pub mdns: MdnsConfig,
+    pub network: NetworkConfig,
+    pub limits: LimitsConfig,
+    pub shutdown: ShutdownConfig,
+    pub share: ShareConfig,
+    pub storage: StorageConfig,
+    pub encryption: EncryptionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub access: AccessConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct AppConfig {
-    // In your TOML, port is "8080" (string).
-    // If you remove quotes in TOML, change this to u16.
-    pub port: String,
+    // Older configs quote the port ("8080"), both forms are accepted.
+    #[serde(deserialize_with = "deserialize_port")]
+    pub port: u16,
     pub debug: bool,
     pub auth : bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            debug: true,
+            auth: false,
+        }
+    }
+}
+
+fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(i64),
+        Text(String),
+    }
+    let text = match Port::deserialize(deserializer)? {
+        Port::Number(port) => port.to_string(),
+        Port::Text(text) => text,
+    };
+    text.parse()
+        .map_err(|_| serde::de::Error::custom(format!("invalid port '{}', expected 1-65535", text)))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct PathConfig {
     pub db: String,
     pub uploads: String,
     pub r#static : String,
 }
 
+impl Default for PathConfig {
+    fn default() -> Self {
+        Self {
+            db: DB_NAME.to_string(),
+            uploads: UPLOAD_DIR.to_string(),
+            r#static: STATIC_DIR.to_string(),
+        }
+    }
+}
+
+/// Limits can be changed while the server runs, see [`crate::reload`],
+/// and through the admin API.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Largest accepted upload, e.g. `"4 GiB"` or a number of bytes.
+    pub max_upload_size: ByteUnit,
+    /// Bytes per second all uploads and downloads share, e.g. `"20 MiB"`.
+    /// Unset for no limit.
+    pub bandwidth: Option<ByteUnit>,
+    /// Bytes per second the uploads and downloads of a single client share
+    pub client_bandwidth: Option<ByteUnit>,
+    /// Uploads and downloads a single client may run at once
+    pub max_transfers_per_client: Option<usize>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_upload_size: 4.gibibytes(),
+            bandwidth: None,
+            client_bandwidth: None,
+            max_transfers_per_client: None,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Values that parse fine but can not work, see [`Config::validate`]
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.max_upload_size == 0 {
+            problems.push("limits.max_upload_size: must be greater than zero".to_string());
+        }
+        for (key, value) in [
+            ("limits.bandwidth", self.bandwidth.map(|b| b.as_u64())),
+            ("limits.client_bandwidth", self.client_bandwidth.map(|b| b.as_u64())),
+            ("limits.max_transfers_per_client", self.max_transfers_per_client.map(|n| n as u64)),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("{}: must be greater than zero, or unset for no limit", key));
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Seconds in-flight uploads and downloads get to finish on shutdown
+    pub grace_period: u32,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { grace_period: DEFAULT_GRACE_PERIOD }
+    }
+}
+
+/// Serving an existing directory in place instead of `uploads/`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShareConfig {
+    /// Directory to serve read-only, relative to the working directory.
+    /// Uploads and deletes are disabled while it is set.
+    pub directory: Option<String>,
+    /// Seconds between scans that pick up files added to or removed from the directory
+    pub rescan_interval: u32,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            rescan_interval: DEFAULT_RESCAN_INTERVAL,
+        }
+    }
+}
+
+/// Where the content of uploaded files is kept, see [`crate::storage`]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Used by the `s3` backend
+    pub s3: S3Config,
+    pub compression: CompressionConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files in `path.uploads`
+    #[default]
+    Local,
+    /// An S3 compatible object store, e.g. MinIO on a NAS
+    S3,
+}
+
+/// Bucket of the `s3` backend. The credentials are read from
+/// `LOCALSHARE_S3_ACCESS_KEY` and `LOCALSHARE_S3_SECRET_KEY`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct S3Config {
+    /// e.g. `"http://nas.local:9000"`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to every object key, e.g. `"localshare/"`
+    pub prefix: String,
+    /// Addresses the bucket as `endpoint/bucket` rather than `bucket.endpoint`,
+    /// which most self-hosted stores expect
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: DEFAULT_S3_REGION.to_string(),
+            prefix: String::new(),
+            path_style: true,
+        }
+    }
+}
+
+/// zstd compression of uploads, see [`crate::compression`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// 1 (fastest) to [`MAX_COMPRESSION_LEVEL`] (smallest)
+    pub level: i32,
+    /// MIME types worth compressing, guessed from the file name.
+    /// `"text/*"` matches every subtype.
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: DEFAULT_COMPRESSION_LEVEL,
+            types: [
+                "text/*",
+                "application/json",
+                "application/xml",
+                "application/javascript",
+                "application/x-ndjson",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// Encryption of uploads at rest, see [`crate::crypto`]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// Encrypts new uploads. Needs `key_file` or `LOCALSHARE_ENCRYPTION_PASSPHRASE`.
+    pub enabled: bool,
+    /// File with at least 32 random bytes, relative to the working directory.
+    /// Takes precedence over the passphrase.
+    pub key_file: Option<String>,
+}
+
+/// Requests per client IP, see [`crate::ratelimit`].
+/// Can be changed while the server runs.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Clients that are never limited
+    pub allow: Vec<IpRange>,
+    /// Clients that are always refused
+    pub deny: Vec<IpRange>,
+    pub upload: RateRule,
+    pub download: RateRule,
+    /// Logging in, where guessing passwords is the concern
+    pub auth: RateRule,
+    /// Every other page and endpoint
+    pub other: RateRule,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow: vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+            deny: Vec::new(),
+            upload: RateRule::new(60, 60),
+            download: RateRule::new(300, 60),
+            auth: RateRule::new(10, 60),
+            other: RateRule::new(600, 60),
+        }
+    }
+}
+
+/// Networks clients may connect from, see [`crate::access`].
+/// Without any entry every client may. Can be changed while the server runs.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Addresses and CIDR ranges, e.g. `["192.168.1.0/24"]`
+    pub allow: Vec<IpRange>,
+    /// Interfaces whose subnets are allowed, e.g. `["eth0"]`
+    pub interfaces: Vec<String>,
+    /// Allows the subnet of the address shown in the QR code, following it
+    /// when the host moves to another network
+    pub local_subnet: bool,
+}
+
+impl AccessConfig {
+    /// Whether any client is refused at all
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty() || !self.interfaces.is_empty() || self.local_subnet
+    }
+}
+
+/// `requests` per `period` seconds, of which all may come at once
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RateRule {
+    pub requests: u32,
+    pub period: u32,
+}
+
+impl RateRule {
+    pub const fn new(requests: u32, period: u32) -> Self {
+        Self { requests, period }
+    }
+}
+
+/// A single address or a CIDR range, e.g. `"192.168.1.20"` or `"192.168.1.0/24"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange(IpNet);
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket arrive as ::ffff:a.b.c.d
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            Ok(Self(net.trunc()))
+        } else if let Ok(ip) = s.parse::<IpAddr>() {
+            Ok(Self(IpNet::from(ip)))
+        } else {
+            Err(format!("invalid address '{}', expected \"ip\" or \"ip/prefix\"", s))
+        }
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(value: IpRange) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // single addresses are written as they were given
+        if self.0.prefix_len() == self.0.max_prefix_len() {
+            write!(f, "{}", self.0.addr())
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct MdnsConfig {
+    /// Service instance name announced on the network.
+    /// When unset, a name derived from the machine hostname is used.
+    pub instance_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Interfaces to advertise on, e.g. `["eth0", "wlan0"]`. Empty means all of them.
+    pub interfaces: Vec<String>,
+    /// Interfaces that are never advertised, e.g. VPN tunnels.
+    pub exclude_interfaces: Vec<String>,
+    /// Whether IPv6 addresses are advertised next to IPv4 ones.
+    pub ipv6: bool,
+    /// Fixed addresses to advertise instead of the detected ones.
+    pub addresses: Vec<IpAddr>,
+    /// Addresses to listen on, either `"ip"` (uses the app port) or `"ip:port"`.
+    /// Use `"::"` for IPv6 and list several entries to listen on several sockets.
+    pub bind: Vec<BindAddr>,
+    /// Unix domain socket to listen on, relative to the working directory,
+    /// for deployments behind a reverse proxy.
+    pub unix_socket: Option<String>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            ipv6: true,
+            addresses: Vec::new(),
+            bind: vec![DEFAULT_BIND],
+            unix_socket: None,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Resolves the `bind` entries, filling in `default_port` where no port is given.
+    pub fn bind_addrs(&self, default_port: u16) -> Vec<SocketAddr> {
+        self.bind
+            .iter()
+            .map(|entry| match *entry {
+                BindAddr::Ip(ip) => SocketAddr::new(ip, default_port),
+                BindAddr::Socket(addr) => addr,
+            })
+            .collect()
+    }
+}
+
+/// A listening address, with or without its own port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddr {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(Self::Socket(addr))
+        } else if let Ok(ip) = s.parse() {
+            Ok(Self::Ip(ip))
+        } else {
+            Err(format!("invalid bind address '{}', expected \"ip\" or \"ip:port\"", s))
+        }
+    }
+}
+
+impl TryFrom<String> for BindAddr {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BindAddr> for String {
+    fn from(value: BindAddr) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{}", ip),
+            Self::Socket(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
 // 2. Default implementation for your 'new' command
 impl Default for Config {
     fn default() -> Self {
         Self {
             version: env!("CARGO_PKG_VERSION").to_string(),
-            app: AppConfig {
-                port: DEFAULT_PORT.to_string(),
-                debug: true,
-                auth: false,
-            },
-            path: PathConfig {
-                db: DB_NAME.to_string(),
-                uploads: "uploads".to_string(),
-                r#static: STATIC_DIR.to_string(),
-            },
+            app: AppConfig::default(),
+            path: PathConfig::default(),
+            mdns: MdnsConfig::default(),
+            network: NetworkConfig::default(),
+            limits: LimitsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            share: ShareConfig::default(),
+            storage: StorageConfig::default(),
+            encryption: EncryptionConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            access: AccessConfig::default(),
+        }
+    }
+}
+
+/// Settings given on the command line or through `LOCALSHARE_*` environment
+/// variables. They take precedence over `LocalShare.toml`.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub port: Option<u16>,
+    pub debug: Option<bool>,
+    pub auth: Option<bool>,
+    pub bind: Option<Vec<BindAddr>>,
+    pub unix_socket: Option<String>,
+    pub instance_name: Option<String>,
+    pub ipv6: Option<bool>,
+    /// Also enables encryption
+    pub key_file: Option<String>,
+}
+
+impl Overrides {
+    /// Reads the overrides from `LOCALSHARE_*` environment variables.
+    /// Invalid values are reported with the offending variable name.
+    pub fn from_env() -> anyhow::Result<Self> {
+        fn var(name: &str) -> Option<String> {
+            std::env::var(name).ok().filter(|v| !v.is_empty())
+        }
+        fn parse<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+        where
+            T::Err: fmt::Display,
+        {
+            var(name)
+                .map(|v| v.parse::<T>().map_err(|e| anyhow::anyhow!("{}: invalid value '{}': {}", name, v, e)))
+                .transpose()
+        }
+        let bind = var("LOCALSHARE_BIND")
+            .map(|v| {
+                v.split(',')
+                    .map(|entry| entry.trim().parse::<BindAddr>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| anyhow::anyhow!("LOCALSHARE_BIND: {}", e))
+            })
+            .transpose()?;
+        Ok(Self {
+            port: parse("LOCALSHARE_PORT")?,
+            debug: parse("LOCALSHARE_DEBUG")?,
+            auth: parse("LOCALSHARE_AUTH")?,
+            bind,
+            unix_socket: var("LOCALSHARE_UNIX_SOCKET"),
+            instance_name: var("LOCALSHARE_INSTANCE_NAME"),
+            ipv6: parse("LOCALSHARE_IPV6")?,
+            key_file: var("LOCALSHARE_KEY_FILE"),
+        })
+    }
+
+    /// Combines two sets of overrides, values in `other` win.
+    pub fn merge(self, other: Overrides) -> Self {
+        Self {
+            port: other.port.or(self.port),
+            debug: other.debug.or(self.debug),
+            auth: other.auth.or(self.auth),
+            bind: other.bind.or(self.bind),
+            unix_socket: other.unix_socket.or(self.unix_socket),
+            instance_name: other.instance_name.or(self.instance_name),
+            ipv6: other.ipv6.or(self.ipv6),
+            key_file: other.key_file.or(self.key_file),
         }
     }
 }
 
+impl Config {
+    pub fn apply(&mut self, overrides: Overrides) {
+        if let Some(port) = overrides.port {
+            self.app.port = port;
+        }
+        if let Some(debug) = overrides.debug {
+            self.app.debug = debug;
+        }
+        if let Some(auth) = overrides.auth {
+            self.app.auth = auth;
+        }
+        if let Some(bind) = overrides.bind {
+            self.network.bind = bind;
+        }
+        if let Some(unix_socket) = overrides.unix_socket {
+            self.network.unix_socket = Some(unix_socket);
+        }
+        if let Some(instance_name) = overrides.instance_name {
+            self.mdns.instance_name = Some(instance_name);
+        }
+        if let Some(ipv6) = overrides.ipv6 {
+            self.network.ipv6 = ipv6;
+        }
+        if let Some(key_file) = overrides.key_file {
+            self.encryption.key_file = Some(key_file);
+            self.encryption.enabled = true;
+        }
+    }
+
+    /// Checks the values that parse fine but can not work.
+    /// Every problem is reported with the key it belongs to.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = self.limits.problems();
+        if self.app.port == 0 {
+            problems.push("app.port: must be between 1 and 65535".to_string());
+        }
+        for (key, value) in [
+            ("path.db", &self.path.db),
+            ("path.uploads", &self.path.uploads),
+            ("path.static", &self.path.r#static),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{}: must not be empty", key));
+            }
+        }
+        if let Some(name) = &self.mdns.instance_name {
+            if name.trim().is_empty() {
+                problems.push("mdns.instance_name: must not be empty".to_string());
+            } else if name.len() > 63 {
+                problems.push("mdns.instance_name: must be at most 63 bytes".to_string());
+            }
+        }
+        for name in &self.network.interfaces {
+            if self.network.exclude_interfaces.contains(name) {
+                problems.push(format!(
+                    "network.exclude_interfaces: '{}' is also listed in network.interfaces",
+                    name
+                ));
+            }
+        }
+        if self.network.bind.is_empty() && self.network.unix_socket.is_none() {
+            problems.push("network.bind: must not be empty unless network.unix_socket is set".to_string());
+        }
+        if let Some(socket) = &self.network.unix_socket
+            && socket.trim().is_empty()
+        {
+            problems.push("network.unix_socket: must not be empty".to_string());
+        }
+        if let Some(directory) = &self.share.directory
+            && directory.trim().is_empty()
+        {
+            problems.push("share.directory: must not be empty".to_string());
+        }
+        if self.share.rescan_interval == 0 {
+            problems.push("share.rescan_interval: must be greater than zero".to_string());
+        }
+        if self.storage.backend == StorageBackend::S3 {
+            for (key, value) in [
+                ("storage.s3.endpoint", &self.storage.s3.endpoint),
+                ("storage.s3.bucket", &self.storage.s3.bucket),
+                ("storage.s3.region", &self.storage.s3.region),
+            ] {
+                if value.trim().is_empty() {
+                    problems.push(format!("{}: must be set for the s3 backend", key));
+                }
+            }
+        }
+        if self.access.interfaces.iter().any(|name| name.trim().is_empty()) {
+            problems.push("access.interfaces: names must not be empty".to_string());
+        }
+        if self.encryption.key_file.as_ref().is_some_and(|f| f.trim().is_empty()) {
+            problems.push("encryption.key_file: must not be empty".to_string());
+        }
+        for (key, rule) in [
+            ("rate_limit.upload", self.rate_limit.upload),
+            ("rate_limit.download", self.rate_limit.download),
+            ("rate_limit.auth", self.rate_limit.auth),
+            ("rate_limit.other", self.rate_limit.other),
+        ] {
+            if rule.requests == 0 || rule.period == 0 {
+                problems.push(format!("{}: requests and period must be greater than zero", key));
+            }
+        }
+        if !(1..=MAX_COMPRESSION_LEVEL).contains(&self.storage.compression.level) {
+            problems.push(format!(
+                "storage.compression.level: must be between 1 and {}",
+                MAX_COMPRESSION_LEVEL
+            ));
+        }
+        for media_type in &self.storage.compression.types {
+            if !media_type.split_once('/').is_some_and(|(t, s)| !t.is_empty() && !s.is_empty()) {
+                problems.push(format!(
+                    "storage.compression.types: '{}' is not a MIME type like \"text/csv\"",
+                    media_type
+                ));
+            }
+        }
+        if !problems.is_empty() {
+            anyhow::bail!("Invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
 
 pub mod utils {
     use std::path::Path;
@@ -61,7 +700,7 @@ pub mod utils {
 
     impl super::Config {
         pub async fn write_path(self, root: &Path) -> io::Result<()> {
-            let toml_string = toml::to_string_pretty(&self).map_err(|e| io::Error::other(e))?;
+            let toml_string = toml::to_string_pretty(&self).map_err(io::Error::other)?;
             let file_path = root.join(CONFIG_FNAME);
             fs::write(file_path, toml_string).await?;
             Ok(())
@@ -74,12 +713,19 @@ pub mod utils {
                 .await
                 .context(format!("Could not read config file at {:?}", file_path))?;
 
-            // 2. Deserialize from string
-            let config: Self =
-                toml::from_str(&content).context("Failed to parse Localshare.toml syntax")?;
-
+            // 2. Deserialize from string, errors point at the offending key
+            let config: Self = toml::from_str(&content)
+                .context(format!("Invalid {}", CONFIG_FNAME))?;
 
+            Ok(config)
+        }
 
+        /// Reads the config, applies environment and command line overrides
+        /// and validates the result.
+        pub async fn load(root: &Path, cli: super::Overrides) -> anyhow::Result<Self> {
+            let mut config = Self::read_path(root).await?;
+            config.apply(super::Overrides::from_env()?.merge(cli));
+            config.validate()?;
             Ok(config)
         }
     }
@@ -0,0 +1,738 @@
+//! This module encrypts stored files at rest, see `encryption`
+//!
+//! Every file gets a random data key, sealed with the master key in the blob
+//! header. The content follows in chunks of [`CHUNK_SIZE`] bytes, each sealed on
+//! its own with XChaCha20-Poly1305 under a nonce made of a per-file prefix, the
+//! chunk index and a flag marking the last chunk. Chunks can be opened
+//! independently, so ranges are served without decrypting the whole file, and
+//! truncated or reordered content fails to open.
+//!
+//! The master key is read from `encryption.key_file` or derived with Argon2id
+//! from the passphrase in `LOCALSHARE_ENCRYPTION_PASSPHRASE`. The salt and a
+//! check value of the key are kept in the database, so a wrong key is refused
+//! on startup instead of failing downloads.
+
+use std::{
+    io::{self, Read},
+    ops::Range,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use anyhow::Context as _;
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng, Payload, rand_core::RngCore},
+};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    config::EncryptionConfig,
+    fm::FileManager,
+    storage::{BlobReader, Storage},
+};
+
+/// Content bytes per chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+/// A chunk as stored, content and tag
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+/// Identifies the blob format, also authenticated with the data key
+const MAGIC: &[u8; 8] = b"LSENC001";
+const KEY_NONCE_SIZE: usize = 24;
+const SEALED_KEY_SIZE: usize = 32 + TAG_SIZE;
+/// Nonces of chunks are this prefix, the chunk index and the last chunk flag
+const PREFIX_SIZE: usize = 19;
+/// Bytes before the first chunk
+pub const HEADER_SIZE: usize = MAGIC.len() + KEY_NONCE_SIZE + SEALED_KEY_SIZE + PREFIX_SIZE;
+
+/// Environment variable holding the passphrase, when no key file is configured
+pub const PASSPHRASE_VAR: &str = "LOCALSHARE_ENCRYPTION_PASSPHRASE";
+/// Shortest key file accepted, in bytes
+const MIN_KEY_FILE_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+
+/// Key the data keys of all files are sealed with
+pub struct MasterKey(Key);
+
+impl MasterKey {
+    fn from_key_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path).context(format!("Could not read key file {}", path.display()))?;
+        if content.len() < MIN_KEY_FILE_SIZE {
+            anyhow::bail!(
+                "Key file {} is too short, it needs at least {} random bytes",
+                path.display(),
+                MIN_KEY_FILE_SIZE
+            );
+        }
+        let digest = Sha256::new()
+            .chain_update(b"localshare key file\0")
+            .chain_update(&content)
+            .finalize();
+        Ok(Self(digest))
+    }
+
+    fn from_passphrase(passphrase: &str, salt: &[u8]) -> anyhow::Result<Self> {
+        let mut key = Key::default();
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow::anyhow!("Could not derive the key from the passphrase: {}", e))?;
+        Ok(Self(key))
+    }
+
+    /// Tells keys apart without revealing them
+    fn check_value(&self) -> Vec<u8> {
+        Sha256::new()
+            .chain_update(b"localshare key check\0")
+            .chain_update(self.0)
+            .finalize()
+            .to_vec()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0)
+    }
+}
+
+/// Loads the master key when a key file or passphrase is given. Blocking.
+/// Fails when encryption is enabled without one, and when it is not the key
+/// earlier files were encrypted with.
+pub fn load_key(
+    workdir: &Path,
+    config: &EncryptionConfig,
+    fm: &FileManager,
+) -> anyhow::Result<Option<MasterKey>> {
+    load(workdir, config, fm, true)
+}
+
+/// Like [`load_key`], but never stores the parameters of a new key,
+/// for tools that must not change the database
+pub fn verify_key(
+    workdir: &Path,
+    config: &EncryptionConfig,
+    fm: &FileManager,
+) -> anyhow::Result<Option<MasterKey>> {
+    load(workdir, config, fm, false)
+}
+
+fn load(
+    workdir: &Path,
+    config: &EncryptionConfig,
+    fm: &FileManager,
+    persist: bool,
+) -> anyhow::Result<Option<MasterKey>> {
+    let passphrase = std::env::var(PASSPHRASE_VAR).ok().filter(|p| !p.is_empty());
+    let params = fm.encryption_params()?;
+    if config.key_file.is_none() && passphrase.is_none() {
+        if config.enabled {
+            anyhow::bail!(
+                "encryption.enabled needs a key: set encryption.key_file or {}",
+                PASSPHRASE_VAR
+            );
+        }
+        if params.is_some() {
+            log::warn!("no encryption key given, encrypted files can not be downloaded");
+        }
+        return Ok(None);
+    }
+    let salt = match &params {
+        Some((salt, _)) => salt.clone(),
+        None => {
+            let mut salt = vec![0; SALT_SIZE];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        }
+    };
+    let key = match (&config.key_file, passphrase) {
+        (Some(key_file), _) => MasterKey::from_key_file(&workdir.join(key_file))?,
+        (None, Some(passphrase)) => MasterKey::from_passphrase(&passphrase, &salt)?,
+        (None, None) => unreachable!(),
+    };
+    match params {
+        Some((_, check)) if check != key.check_value() => anyhow::bail!(
+            "The encryption key does not match the key files were encrypted with"
+        ),
+        Some(_) => {}
+        None if persist => fm.set_encryption_params(&salt, &key.check_value())?,
+        None => {}
+    }
+    Ok(Some(key))
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Opens the chunks of one file
+struct Opener {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    /// index of the last chunk
+    last: u64,
+}
+
+fn chunk_nonce(prefix: &[u8; PREFIX_SIZE], index: u64, last: bool) -> io::Result<XNonce> {
+    let index = u32::try_from(index).map_err(|_| invalid("encrypted file has too many chunks"))?;
+    let mut nonce = XNonce::default();
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..PREFIX_SIZE + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[PREFIX_SIZE + 4] = last as u8;
+    Ok(nonce)
+}
+
+impl Opener {
+    /// Reads the header of a blob of `stored_size` bytes
+    fn new(master: &MasterKey, header: &[u8; HEADER_SIZE], stored_size: u64) -> io::Result<Self> {
+        let (magic, rest) = header.split_at(MAGIC.len());
+        let (key_nonce, rest) = rest.split_at(KEY_NONCE_SIZE);
+        let (sealed_key, prefix) = rest.split_at(SEALED_KEY_SIZE);
+        if magic != MAGIC {
+            return Err(invalid("not an encrypted file"));
+        }
+        let data_key = master
+            .cipher()
+            .decrypt(XNonce::from_slice(key_nonce), Payload { msg: sealed_key, aad: MAGIC })
+            .map_err(|_| invalid("file was encrypted with another key"))?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&data_key)),
+            prefix: prefix.try_into().unwrap(),
+            last: chunk_count(stored_size)? - 1,
+        })
+    }
+
+    fn open(&self, index: u64, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if index > self.last {
+            return Err(invalid("encrypted file is longer than expected"));
+        }
+        let nonce = chunk_nonce(&self.prefix, index, index == self.last)?;
+        self.cipher
+            .decrypt(&nonce, sealed)
+            .map_err(|_| invalid("encrypted file is corrupted"))
+    }
+}
+
+/// Number of chunks in a blob of `stored_size` bytes, at least one
+fn chunk_count(stored_size: u64) -> io::Result<u64> {
+    let sealed = stored_size
+        .checked_sub(HEADER_SIZE as u64)
+        .filter(|&sealed| sealed >= TAG_SIZE as u64)
+        .ok_or_else(|| invalid("encrypted file is truncated"))?;
+    Ok(sealed.div_ceil(SEALED_CHUNK_SIZE as u64))
+}
+
+/// Size of the content of a blob of `stored_size` bytes
+pub fn content_size(stored_size: u64) -> io::Result<u64> {
+    let chunks = chunk_count(stored_size)?;
+    Ok(stored_size - HEADER_SIZE as u64 - chunks * TAG_SIZE as u64)
+}
+
+/// Writes what it is given to `inner` encrypted. Must be shut down, which seals
+/// the last chunk.
+pub struct EncryptingWriter<W> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    index: u64,
+    /// content of the chunk being filled
+    plain: Vec<u8>,
+    /// sealed bytes not yet taken by `inner`, starting with the header
+    sealed: Vec<u8>,
+    written: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    pub fn new(inner: W, master: &MasterKey) -> Self {
+        let data_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let key_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed_key = master
+            .cipher()
+            .encrypt(&key_nonce, Payload { msg: &data_key, aad: MAGIC })
+            .expect("sealing a key can not fail");
+        let mut prefix = [0; PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&key_nonce);
+        header.extend_from_slice(&sealed_key);
+        header.extend_from_slice(&prefix);
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(&data_key),
+            prefix,
+            index: 0,
+            plain: Vec::with_capacity(CHUNK_SIZE),
+            sealed: header,
+            written: 0,
+            finished: false,
+        }
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.index, last)?;
+        self.sealed = self
+            .cipher
+            .encrypt(&nonce, self.plain.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.written = 0;
+        self.plain.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    fn poll_write_sealed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.sealed.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.sealed[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_sealed(cx))?;
+        // a full chunk is only sealed once more content follows, the last one on shutdown
+        if this.plain.len() == CHUNK_SIZE && !buf.is_empty() {
+            this.seal(false)?;
+            ready!(this.poll_write_sealed(cx))?;
+        }
+        let n = buf.len().min(CHUNK_SIZE - this.plain.len());
+        this.plain.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_sealed(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_sealed(cx))?;
+        if !this.finished {
+            this.seal(true)?;
+            this.finished = true;
+            ready!(this.poll_write_sealed(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decrypts consecutive sealed chunks read from `inner`
+struct DecryptingReader<R> {
+    inner: R,
+    opener: Opener,
+    index: u64,
+    sealed: Vec<u8>,
+    filled: usize,
+    plain: Vec<u8>,
+    pos: usize,
+    /// content bytes to drop from the first chunk
+    skip: usize,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.pos);
+                buf.put_slice(&this.plain[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            // the last chunk ends the blob, the others are full
+            let mut eof = false;
+            while this.filled < SEALED_CHUNK_SIZE {
+                let mut read = ReadBuf::new(&mut this.sealed[this.filled..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                if read.filled().is_empty() {
+                    eof = true;
+                    break;
+                }
+                this.filled += read.filled().len();
+            }
+            // reading stops after the last chunk or once a range is complete
+            if this.filled == 0 && eof {
+                return Poll::Ready(Err(invalid("encrypted file is truncated")));
+            }
+            this.plain = this.opener.open(this.index, &this.sealed[..this.filled])?;
+            this.pos = std::mem::take(&mut this.skip).min(this.plain.len());
+            this.done = this.index == this.opener.last;
+            this.index += 1;
+            this.filled = 0;
+        }
+    }
+}
+
+/// Reads `range` of the content of the encrypted blob stored under `key`,
+/// all of it if `None`. `stored_size` is the size of the blob.
+pub async fn open(
+    storage: &dyn Storage,
+    key: &str,
+    master: &MasterKey,
+    stored_size: u64,
+    range: Option<Range<u64>>,
+) -> io::Result<BlobReader> {
+    let mut header = [0; HEADER_SIZE];
+    storage
+        .get(key, Some(0..HEADER_SIZE as u64))
+        .await?
+        .read_exact(&mut header)
+        .await?;
+    let opener = Opener::new(master, &header, stored_size)?;
+    let chunk = CHUNK_SIZE as u64;
+    let sealed_chunk = SEALED_CHUNK_SIZE as u64;
+    let (first, skip, length) = match &range {
+        Some(range) => (range.start / chunk, range.start % chunk, Some(range.end - range.start)),
+        None => (0, 0, None),
+    };
+    // whole chunks, from the first one the range touches to the one it ends in
+    let end = match &range {
+        Some(range) if !range.is_empty() => HEADER_SIZE as u64 + range.end.div_ceil(chunk) * sealed_chunk,
+        Some(_) => return Ok(Box::pin(tokio::io::empty())),
+        None => stored_size,
+    };
+    let start = HEADER_SIZE as u64 + first * sealed_chunk;
+    let sealed = storage.get(key, Some(start..end.min(stored_size))).await?;
+    let reader = DecryptingReader {
+        inner: sealed,
+        opener,
+        index: first,
+        sealed: vec![0; SEALED_CHUNK_SIZE],
+        filled: 0,
+        plain: Vec::new(),
+        pos: 0,
+        skip: skip as usize,
+        done: false,
+    };
+    Ok(match length {
+        Some(length) => Box::pin(reader.take(length)),
+        None => Box::pin(reader),
+    })
+}
+
+/// Writes to `file`, encrypting with `key` if given, buffered either way
+pub fn writer(file: tokio::fs::File, key: Option<&MasterKey>) -> Box<dyn AsyncWrite + Send + Unpin> {
+    match key {
+        // whole chunks are written at once
+        Some(key) => Box::new(EncryptingWriter::new(file, key)),
+        None => Box::new(tokio::io::BufWriter::new(file)),
+    }
+}
+
+/// Blocking reader of the content of an encrypted file, for `localshare fsck`
+pub struct FileDecryptor {
+    file: std::fs::File,
+    opener: Opener,
+    index: u64,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl FileDecryptor {
+    pub fn open(path: &Path, master: &MasterKey) -> io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let stored_size = file.metadata()?.len();
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        Ok(Self {
+            opener: Opener::new(master, &header, stored_size)?,
+            file,
+            index: 0,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+}
+
+impl Read for FileDecryptor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let mut sealed = Vec::with_capacity(SEALED_CHUNK_SIZE);
+            (&mut self.file)
+                .take(SEALED_CHUNK_SIZE as u64)
+                .read_to_end(&mut sealed)?;
+            self.plain = self.opener.open(self.index, &sealed)?;
+            self.pos = 0;
+            self.done = self.index == self.opener.last;
+            self.index += 1;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    const SIZES: [usize; 8] = [
+        0,
+        1,
+        CHUNK_SIZE - 1,
+        CHUNK_SIZE,
+        CHUNK_SIZE + 1,
+        2 * CHUNK_SIZE,
+        3 * CHUNK_SIZE,
+        3 * CHUNK_SIZE + 100,
+    ];
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey(Key::clone_from_slice(&[byte; 32]))
+    }
+
+    fn content(size: usize) -> Vec<u8> {
+        (0..size).map(|n| (n * 7 % 251) as u8).collect()
+    }
+
+    async fn encrypt(plain: &[u8], master: &MasterKey) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), master);
+        writer.write_all(plain).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.inner
+    }
+
+    /// Stores `blob` and reads `range` of it through [`open`],
+    /// as if the record said the blob is `stored_size` bytes long
+    async fn read_stored(
+        blob: &[u8],
+        stored_size: u64,
+        master: &MasterKey,
+        range: Option<Range<u64>>,
+    ) -> io::Result<Vec<u8>> {
+        let dir = tempfile::tempdir()?;
+        let storage = LocalStorage::new(dir.path().to_path_buf());
+        storage.put("blob", &mut &blob[..]).await?;
+        let mut reader = open(&storage, "blob", master, stored_size, range).await?;
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).await?;
+        Ok(plain)
+    }
+
+    async fn read(blob: &[u8], master: &MasterKey, range: Option<Range<u64>>) -> io::Result<Vec<u8>> {
+        read_stored(blob, blob.len() as u64, master, range).await
+    }
+
+    fn read_file(blob: &[u8], master: &MasterKey) -> io::Result<Vec<u8>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("blob");
+        std::fs::write(&path, blob)?;
+        let mut plain = Vec::new();
+        FileDecryptor::open(&path, master)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let master = key(1);
+        for size in SIZES {
+            let plain = content(size);
+            let sealed = encrypt(&plain, &master).await;
+            let chunks = size.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(sealed.len(), HEADER_SIZE + size + chunks * TAG_SIZE, "size {}", size);
+            assert_eq!(content_size(sealed.len() as u64).unwrap(), size as u64);
+            assert_eq!(read(&sealed, &master, None).await.unwrap(), plain, "size {}", size);
+            assert_eq!(read_file(&sealed, &master).unwrap(), plain, "size {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges() {
+        let master = key(1);
+        let size = 3 * CHUNK_SIZE + 100;
+        let plain = content(size);
+        let sealed = encrypt(&plain, &master).await;
+        let (chunk, size) = (CHUNK_SIZE as u64, size as u64);
+        let ranges = [
+            // within one chunk
+            0..1,
+            10..20,
+            // exactly one chunk
+            0..chunk,
+            chunk..2 * chunk,
+            // starting or ending on a boundary
+            chunk..chunk + 1,
+            chunk - 1..chunk,
+            2 * chunk..size,
+            // crossing boundaries
+            chunk - 1..chunk + 1,
+            10..2 * chunk + 10,
+            1..size - 1,
+            // the last chunk
+            3 * chunk..size,
+            size - 1..size,
+            0..size,
+            // empty
+            5..5,
+            chunk..chunk,
+        ];
+        for range in ranges {
+            let expected = &plain[range.start as usize..range.end as usize];
+            let read = read(&sealed, &master, Some(range.clone())).await.unwrap();
+            assert_eq!(read, expected, "range {:?}", range);
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_of_exact_multiples() {
+        let master = key(1);
+        let plain = content(2 * CHUNK_SIZE);
+        let sealed = encrypt(&plain, &master).await;
+        let chunk = CHUNK_SIZE as u64;
+        for range in [0..chunk, chunk..2 * chunk, chunk - 1..chunk + 1, 2 * chunk - 1..2 * chunk] {
+            let expected = &plain[range.start as usize..range.end as usize];
+            assert_eq!(read(&sealed, &master, Some(range.clone())).await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let master = key(1);
+        let sealed = encrypt(&content(3 * CHUNK_SIZE + 100), &master).await;
+        for len in [
+            // at chunk boundaries
+            HEADER_SIZE + 3 * SEALED_CHUNK_SIZE,
+            HEADER_SIZE + SEALED_CHUNK_SIZE,
+            // within a chunk
+            HEADER_SIZE + SEALED_CHUNK_SIZE + 10,
+            sealed.len() - 1,
+            // nothing but the header, or not even that
+            HEADER_SIZE + TAG_SIZE - 1,
+            HEADER_SIZE,
+            HEADER_SIZE - 1,
+            0,
+        ] {
+            let cut = &sealed[..len];
+            assert!(read(cut, &master, None).await.is_err(), "cut at {}", len);
+            assert!(read_file(cut, &master).is_err(), "cut at {}", len);
+            // the record still has the original size
+            let result = read_stored(cut, sealed.len() as u64, &master, None).await;
+            assert!(result.is_err(), "cut at {}", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn reordered() {
+        let master = key(1);
+        let sealed = encrypt(&content(3 * CHUNK_SIZE + 100), &master).await;
+        let mut swapped = sealed.clone();
+        let (first, second) = swapped[HEADER_SIZE..].split_at_mut(SEALED_CHUNK_SIZE);
+        first.swap_with_slice(&mut second[..SEALED_CHUNK_SIZE]);
+        assert!(read(&swapped, &master, None).await.is_err());
+        assert!(read_file(&swapped, &master).is_err());
+        let chunk = CHUNK_SIZE as u64;
+        assert!(read(&swapped, &master, Some(chunk..chunk + 10)).await.is_err());
+
+        // a full chunk passed off as the last one
+        let cut = &sealed[..HEADER_SIZE + 2 * SEALED_CHUNK_SIZE];
+        assert!(read(cut, &master, Some(chunk..chunk + 10)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered() {
+        let master = key(1);
+        let sealed = encrypt(&content(2 * CHUNK_SIZE), &master).await;
+        for pos in [0, MAGIC.len() + 1, HEADER_SIZE - 1, HEADER_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[pos] ^= 1;
+            assert!(read(&tampered, &master, None).await.is_err(), "byte {}", pos);
+            assert!(read_file(&tampered, &master).is_err(), "byte {}", pos);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        let sealed = encrypt(&content(100), &key(1)).await;
+        let error = read(&sealed, &key(2), None).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(read(&sealed, &key(2), Some(0..10)).await.is_err());
+        assert!(read_file(&sealed, &key(2)).is_err());
+    }
+
+    /// Takes a few bytes per write, and is not ready every other time
+    #[derive(Default)]
+    struct Choppy {
+        written: Vec<u8>,
+        ready: bool,
+    }
+
+    impl Choppy {
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            self.ready = !self.ready;
+            if self.ready {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncWrite for Choppy {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            ready!(this.poll_ready(cx));
+            let n = buf.len().min(7);
+            this.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            ready!(self.get_mut().poll_ready(cx));
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            ready!(self.get_mut().poll_ready(cx));
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_sink() {
+        let master = key(1);
+        for size in SIZES {
+            let plain = content(size);
+            let mut writer = EncryptingWriter::new(Choppy::default(), &master);
+            // odd sized writes, so they never line up with chunks
+            for piece in plain.chunks(1000) {
+                writer.write_all(piece).await.unwrap();
+                writer.flush().await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+            let sealed = writer.inner.written;
+            assert_eq!(read(&sealed, &master, None).await.unwrap(), plain, "size {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn each_file_gets_its_own_key() {
+        let master = key(1);
+        let plain = content(1000);
+        let (a, b) = (encrypt(&plain, &master).await, encrypt(&plain, &master).await);
+        assert_ne!(a[HEADER_SIZE..], b[HEADER_SIZE..]);
+    }
+}
@@ -0,0 +1,200 @@
+//! This module handles SHA-256 digests exchanged with clients
+//!
+//! Uploads can carry the digest the client expects, as a `Content-Digest`
+//! (RFC 9530) or `Digest` (RFC 3230) header or as a hex `sha256` query parameter.
+//! The upload is hashed while it is written and rejected when the digests differ.
+
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::{
+    r#gen::OpenApiGenerator,
+    okapi::openapi3::{Parameter, ParameterValue},
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWrite;
+
+/// A SHA-256 digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha256Digest([u8; 32]);
+
+impl Sha256Digest {
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid sha256 '{}', expected 64 hex digits", hex);
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+
+    fn from_base64(value: &str) -> Result<Self, String> {
+        let bytes = STANDARD
+            .decode(value.trim())
+            .map_err(|_| format!("invalid base64 digest '{}'", value))?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| format!("digest '{}' is not 32 bytes long", value))?;
+        Ok(Self(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Value of a `Content-Digest` header, `sha-256=:<base64>:`
+    pub fn header_value(&self) -> String {
+        format!("sha-256=:{}:", STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Display for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Digest headers of a request, parsed by [`DigestHeaders::expected`]
+#[derive(Debug, Default)]
+pub struct DigestHeaders {
+    content_digest: Option<String>,
+    digest: Option<String>,
+}
+
+impl DigestHeaders {
+    /// The SHA-256 digest the client expects, if it sent any.
+    /// Fails on malformed headers and when only unsupported algorithms are given,
+    /// so a client asking for verification never silently goes without.
+    pub fn expected(&self) -> Result<Option<Sha256Digest>, String> {
+        if let Some(header) = &self.content_digest {
+            // sha-256=:<base64>:, sha-512=:<base64>:
+            return find_sha256(header, "Content-Digest", |value| {
+                value
+                    .strip_prefix(':')
+                    .and_then(|v| v.strip_suffix(':'))
+                    .ok_or_else(|| format!("invalid Content-Digest value '{}'", value))
+                    .and_then(Sha256Digest::from_base64)
+            })
+            .map(Some);
+        }
+        if let Some(header) = &self.digest {
+            // SHA-256=<base64>
+            return find_sha256(header, "Digest", Sha256Digest::from_base64).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+fn find_sha256(
+    header: &str,
+    name: &str,
+    parse: impl Fn(&str) -> Result<Sha256Digest, String>,
+) -> Result<Sha256Digest, String> {
+    for member in header.split(',') {
+        let Some((algorithm, value)) = member.split_once('=') else {
+            return Err(format!("invalid {} header '{}'", name, header));
+        };
+        if algorithm.trim().eq_ignore_ascii_case("sha-256") {
+            return parse(value.trim());
+        }
+    }
+    Err(format!("{} has no sha-256 digest, the only supported algorithm", name))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DigestHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(Self {
+            content_digest: headers.get_one("Content-Digest").map(str::to_string),
+            digest: headers.get_one("Digest").map(str::to_string),
+        })
+    }
+}
+
+/// Documented as `Content-Digest`, the header new clients should send
+impl<'r> OpenApiFromRequest<'r> for DigestHeaders {
+    fn from_request_input(
+        generator: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: "Content-Digest".to_string(),
+            location: "header".to_string(),
+            description: Some(
+                "Expected digest of the body, `sha-256=:<base64>:`. \
+                 The RFC 3230 `Digest: SHA-256=<base64>` header is accepted as well."
+                    .to_string(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: generator.json_schema::<String>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        }))
+    }
+}
+
+/// Writer that hashes everything written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> (W, Sha256Digest) {
+        (self.inner, Sha256Digest(self.hasher.finalize().into()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            // only what the inner writer took, the rest is offered again
+            self.hasher.update(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,329 @@
+//! This module defines the errors the API responds with
+//!
+//! Every error is sent as a JSON object with a stable `code` scripts can match on,
+//! a human readable `message`, optional `details` and the `request_id` that is
+//! also logged and sent as the `X-Request-Id` header.
+
+use rocket::{
+    Request, Response,
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    response::{self, Responder},
+    serde::json::{Json, Value, json},
+};
+use rocket_okapi::{
+    JsonSchema,
+    r#gen::OpenApiGenerator,
+    okapi::openapi3::{RefOr, Responses},
+    response::OpenApiResponderInner,
+    util::add_schema_response,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::fm::ReadOnly;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Errors returned by the API routes
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    InvalidFilename(String),
+    InvalidDigest(String),
+    DigestMismatch { expected: String, actual: String },
+    Unauthorized,
+    WrongPassword,
+    ReadOnly,
+    /// The client may not use the server, see [`crate::ratelimit`]
+    AccessDenied,
+    NotFound(&'static str),
+    /// The record exists but its content is gone
+    FileMissing,
+    /// The file is encrypted and the server was started without the key
+    KeyUnavailable,
+    PayloadTooLarge { limit: u64 },
+    /// The requested range starts past the end of the file
+    RangeNotSatisfiable { size: u64 },
+    /// The client already runs `limits.max_transfers_per_client` transfers
+    TooManyTransfers { limit: usize },
+    /// The client sent too many requests, see [`crate::ratelimit`]
+    RateLimited { retry_after: u64 },
+    Database(anyhow::Error),
+    Io(std::io::Error),
+    Internal(String),
+    /// Any other status, from the default catcher
+    Status(Status),
+}
+
+/// JSON body of every error response
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<Value>,
+    pub request_id: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            Self::BadRequest(_)
+            | Self::InvalidFilename(_)
+            | Self::InvalidDigest(_)
+            | Self::DigestMismatch { .. } => Status::BadRequest,
+            Self::Unauthorized | Self::WrongPassword => Status::Unauthorized,
+            Self::ReadOnly | Self::AccessDenied => Status::Forbidden,
+            Self::NotFound(_) | Self::FileMissing => Status::NotFound,
+            Self::KeyUnavailable => Status::ServiceUnavailable,
+            Self::PayloadTooLarge { .. } => Status::PayloadTooLarge,
+            Self::RangeNotSatisfiable { .. } => Status::RangeNotSatisfiable,
+            Self::TooManyTransfers { .. } | Self::RateLimited { .. } => Status::TooManyRequests,
+            Self::Database(_) | Self::Io(_) | Self::Internal(_) => Status::InternalServerError,
+            Self::Status(status) => *status,
+        }
+    }
+
+    /// Stable machine readable code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::InvalidFilename(_) => "invalid_filename",
+            Self::InvalidDigest(_) => "invalid_digest",
+            Self::DigestMismatch { .. } => "digest_mismatch",
+            Self::Unauthorized => "unauthorized",
+            Self::WrongPassword => "wrong_password",
+            Self::ReadOnly => "read_only",
+            Self::AccessDenied => "access_denied",
+            Self::NotFound(_) => "not_found",
+            Self::FileMissing => "file_missing",
+            Self::KeyUnavailable => "key_unavailable",
+            Self::PayloadTooLarge { .. } => "payload_too_large",
+            Self::RangeNotSatisfiable { .. } => "range_not_satisfiable",
+            Self::TooManyTransfers { .. } => "too_many_transfers",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Database(_) => "database_error",
+            Self::Io(_) => "io_error",
+            Self::Internal(_) => "internal_error",
+            Self::Status(status) => match status.code {
+                400 => "bad_request",
+                401 => "unauthorized",
+                403 => "forbidden",
+                404 => "not_found",
+                413 => "payload_too_large",
+                416 => "range_not_satisfiable",
+                429 => "too_many_requests",
+                503 => "service_unavailable",
+                422 => "unprocessable_entity",
+                500..=599 => "internal_error",
+                _ => "error",
+            },
+        }
+    }
+
+    /// Message for the client, internals stay in the log
+    pub fn message(&self) -> String {
+        match self {
+            Self::BadRequest(message)
+            | Self::InvalidFilename(message)
+            | Self::InvalidDigest(message) => message.clone(),
+            Self::DigestMismatch { .. } => "content does not match the expected sha256".into(),
+            Self::Unauthorized => "a valid session is required".into(),
+            Self::WrongPassword => "wrong password".into(),
+            Self::ReadOnly => "server is read-only".into(),
+            Self::AccessDenied => "this address may not use the server".into(),
+            Self::NotFound(what) => format!("{} not found", what),
+            Self::FileMissing => "file content is missing".into(),
+            Self::KeyUnavailable => "file is encrypted and the server has no key".into(),
+            Self::PayloadTooLarge { .. } => "upload is larger than the server accepts".into(),
+            Self::RangeNotSatisfiable { .. } => "requested range is outside of the file".into(),
+            Self::TooManyTransfers { .. } => "too many transfers from this client at once".into(),
+            Self::RateLimited { .. } => "too many requests, retry later".into(),
+            Self::Database(_) => "database query failed".into(),
+            Self::Io(_) => "file access failed".into(),
+            Self::Internal(_) => "internal server error".into(),
+            Self::Status(status) => status.reason_lossy().to_lowercase(),
+        }
+    }
+
+    pub fn details(&self) -> Option<Value> {
+        match self {
+            Self::DigestMismatch { expected, actual } => {
+                Some(json!({ "expected": expected, "actual": actual }))
+            }
+            Self::PayloadTooLarge { limit } => Some(json!({ "limit": limit })),
+            Self::RangeNotSatisfiable { size } => Some(json!({ "size": size })),
+            Self::TooManyTransfers { limit } => Some(json!({ "limit": limit })),
+            Self::RateLimited { retry_after } => Some(json!({ "retry_after": retry_after })),
+            _ => None,
+        }
+    }
+
+    fn source_description(&self) -> Option<String> {
+        match self {
+            Self::Database(e) => Some(format!("{:#}", e)),
+            Self::Io(e) => Some(e.to_string()),
+            Self::Internal(e) => Some(e.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Errors of [`crate::fm::FileManager`] are `anyhow` errors,
+/// they are told apart by what they wrap
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.downcast_ref::<ReadOnly>().is_some() {
+            return Self::ReadOnly;
+        }
+        if e.downcast_ref::<rusqlite::Error>().is_some() {
+            return Self::Database(e);
+        }
+        match e.downcast::<std::io::Error>() {
+            Ok(io) => Self::Io(io),
+            Err(e) => Self::Internal(format!("{:#}", e)),
+        }
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let status = self.status();
+        let request_id = RequestId::of(request);
+        match self.source_description() {
+            Some(source) => log::error!(
+                "[{}] {} {}: {}: {}",
+                request_id,
+                request.method(),
+                request.uri(),
+                self.code(),
+                source
+            ),
+            None => log::info!(
+                "[{}] {} {}: {}",
+                request_id,
+                request.method(),
+                request.uri(),
+                self.code()
+            ),
+        }
+        let header = match self {
+            Self::RangeNotSatisfiable { size } => Some(("Content-Range", format!("bytes */{}", size))),
+            Self::RateLimited { retry_after } => Some(("Retry-After", retry_after.to_string())),
+            _ => None,
+        };
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            details: self.details(),
+            request_id: request_id.to_string(),
+        };
+        let mut response = Response::build_from(Json(body).respond_to(request)?);
+        response.status(status);
+        if let Some((name, value)) = header {
+            response.raw_header(name, value);
+        }
+        response.ok()
+    }
+}
+
+/// Statuses an API route can fail with, all carrying an [`ErrorBody`]
+impl OpenApiResponderInner for ApiError {
+    fn responses(generator: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = generator.json_schema::<ErrorBody>();
+        for (status, description) in [
+            (400, "Invalid request, see `code` and `message`"),
+            (401, "A valid session is required"),
+            (403, "The server is read-only, or the client may not use it"),
+            (404, "No such record or endpoint"),
+            (413, "The upload exceeds `limits.max_upload_size`"),
+            (416, "The requested range is outside of the file"),
+            (429, "The client sends too many requests or runs too many transfers"),
+            (500, "Internal error, logged under the request id"),
+            (503, "The file is encrypted and the server has no key"),
+        ] {
+            add_schema_response(&mut responses, status, "application/json", schema.clone())?;
+            if let Some(RefOr::Object(response)) = responses.responses.get_mut(&status.to_string()) {
+                response.description = description.to_string();
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// Identifies a request in responses and logs.
+/// Taken from an incoming `X-Request-Id` header when it looks sane, generated otherwise.
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| {
+                let incoming = request.headers().get_one(REQUEST_ID_HEADER).filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= 64
+                        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+                });
+                match incoming {
+                    Some(id) => RequestId(id.to_string()),
+                    None => RequestId(Uuid::new_v4().to_string()),
+                }
+            })
+            .0
+    }
+}
+
+/// Adds the `X-Request-Id` header to every response
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(request).to_string()));
+    }
+}
+
+#[rocket::catch(401)]
+fn unauthorized() -> ApiError {
+    ApiError::Unauthorized
+}
+
+#[rocket::catch(404)]
+fn not_found() -> ApiError {
+    ApiError::NotFound("endpoint")
+}
+
+#[rocket::catch(413)]
+fn payload_too_large() -> ApiError {
+    ApiError::Status(Status::PayloadTooLarge)
+}
+
+#[rocket::catch(500)]
+fn internal_error() -> ApiError {
+    ApiError::Internal("unhandled error, see the log".into())
+}
+
+#[rocket::catch(default)]
+fn default(status: Status, _request: &Request<'_>) -> ApiError {
+    ApiError::Status(status)
+}
+
+/// Catchers answering with [`ErrorBody`], registered for the API
+pub fn catchers() -> Vec<rocket::Catcher> {
+    rocket::catchers![unauthorized, not_found, payload_too_large, internal_error, default]
+}
@@ -0,0 +1,254 @@
+//! This module keeps file names safe to store and to send back in headers
+
+/// Longest stored name in bytes, the common file system limit
+const MAX_LEN: usize = 255;
+
+/// Turns a client supplied name into one that is safe to store and offer for download.
+/// Directory parts, control characters and characters reserved on common file systems
+/// are dropped or replaced. Fails when nothing usable is left.
+pub fn sanitize(name: &str) -> Result<String, String> {
+    // browsers on Windows have been known to send the full path
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned
+        .trim()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+    if cleaned.is_empty() || cleaned.chars().all(|c| c == '.') {
+        return Err(format!("invalid filename '{}'", name.escape_debug()));
+    }
+    if is_reserved(cleaned) {
+        return Ok(truncate(&format!("_{}", cleaned), MAX_LEN));
+    }
+    Ok(truncate(cleaned, MAX_LEN))
+}
+
+/// Device names Windows refuses as file names, whatever the extension
+fn is_reserved(name: &str) -> bool {
+    const RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        return true;
+    }
+    // compared as bytes, a four byte stem may hold characters of any width
+    match stem.as_bytes() {
+        [prefix @ .., digit] if prefix.len() == 3 => {
+            (prefix.eq_ignore_ascii_case(b"COM") || prefix.eq_ignore_ascii_case(b"LPT"))
+                && matches!(digit, b'1'..=b'9')
+        }
+        _ => false,
+    }
+}
+
+/// Shortens `name` to at most `max` bytes, keeping the extension
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let ext = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => &name[i..],
+        _ => "",
+    };
+    let mut end = max - ext.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], ext)
+}
+
+/// `Content-Disposition` value offering `name` as a download, following RFC 6266.
+/// Clients that understand `filename*` get the exact UTF-8 name,
+/// older ones an ASCII approximation.
+pub fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("attachment; filename=\"{}\"", name);
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        percent_encode(name)
+    )
+}
+
+/// Encodes everything but RFC 5987 `attr-char`
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_drops_directories() {
+        assert_eq!(sanitize("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize("C:\\Users\\me\\report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sanitize("dir/..\\notes.txt").unwrap(), "notes.txt");
+        assert!(sanitize("uploads/").is_err());
+        assert!(sanitize("a/..").is_err());
+    }
+
+    #[test]
+    fn sanitize_replaces_unsafe_characters() {
+        assert_eq!(sanitize("a\0b\nc\u{7f}.txt").unwrap(), "abc.txt");
+        assert_eq!(
+            sanitize("what?<a>:\"b\"|*.txt").unwrap(),
+            "what__a___b___.txt"
+        );
+        assert_eq!(
+            sanitize("  spaced name.txt. . ").unwrap(),
+            "spaced name.txt"
+        );
+    }
+
+    #[test]
+    fn sanitize_rejects_empty_and_dot_names() {
+        for name in ["", "   ", ".", "..", "...", "\n\t", ". ."] {
+            assert!(sanitize(name).is_err(), "{:?} was accepted", name);
+        }
+        assert_eq!(sanitize(".hidden").unwrap(), ".hidden");
+    }
+
+    #[test]
+    fn sanitize_escapes_reserved_windows_names() {
+        assert_eq!(sanitize("CON").unwrap(), "_CON");
+        assert_eq!(sanitize("nul.txt").unwrap(), "_nul.txt");
+        assert_eq!(sanitize("Com1.tar.gz").unwrap(), "_Com1.tar.gz");
+        assert_eq!(sanitize("lpt9").unwrap(), "_lpt9");
+        assert_eq!(sanitize("console.log").unwrap(), "console.log");
+        assert_eq!(sanitize("COM0").unwrap(), "COM0");
+        assert_eq!(sanitize("LPT10").unwrap(), "LPT10");
+    }
+
+    #[test]
+    fn sanitize_keeps_non_ascii_names() {
+        for name in [
+            "😀",
+            "😀.png",
+            "éé.txt",
+            "a€.txt",
+            "€a.txt",
+            "写真.jpg",
+            "報告書",
+            "Ñandú.pdf",
+        ] {
+            assert_eq!(sanitize(name).unwrap(), name);
+        }
+        // no reserved name has a non-ASCII letter
+        assert_eq!(sanitize("CÖM1.txt").unwrap(), "CÖM1.txt");
+        assert_eq!(sanitize("COM¹.txt").unwrap(), "COM¹.txt");
+    }
+
+    #[test]
+    fn truncate_keeps_short_names() {
+        assert_eq!(truncate("file.txt", 8), "file.txt");
+    }
+
+    #[test]
+    fn truncate_keeps_the_extension() {
+        let name = format!("{}.txt", "a".repeat(300));
+        let truncated = truncate(&name, MAX_LEN);
+        assert_eq!(truncated.len(), MAX_LEN);
+        assert!(truncated.ends_with(".txt"));
+        // too long to be an extension
+        let name = format!("a.{}", "b".repeat(300));
+        assert_eq!(truncate(&name, 10), format!("a.{}", "b".repeat(8)));
+    }
+
+    #[test]
+    fn truncate_never_splits_characters() {
+        // 2, 3 and 4 byte characters, so every cut position is tried
+        for c in ['é', '€', '😀'] {
+            let name: String = std::iter::repeat_n(c, 100).collect();
+            for max in 1..40 {
+                let truncated = truncate(&name, max);
+                assert!(truncated.len() <= max);
+                assert!(truncated.len() + c.len_utf8() > max);
+                assert!(truncated.chars().all(|t| t == c));
+            }
+        }
+        let name = format!("{}.txt", "😀".repeat(100));
+        let truncated = truncate(&name, 14);
+        assert_eq!(truncated, "😀😀.txt");
+    }
+
+    #[test]
+    fn sanitize_truncates_long_names() {
+        let name = format!("{}.jpg", "ü".repeat(200));
+        let sanitized = sanitize(&name).unwrap();
+        assert!(sanitized.len() <= MAX_LEN);
+        assert!(sanitized.ends_with(".jpg"));
+    }
+
+    #[test]
+    fn content_disposition_ascii() {
+        assert_eq!(
+            content_disposition("report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\""
+        );
+    }
+
+    #[test]
+    fn content_disposition_utf8() {
+        assert_eq!(
+            content_disposition("résumé.pdf"),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+        assert_eq!(
+            content_disposition("😀.png"),
+            "attachment; filename=\"_.png\"; filename*=UTF-8''%F0%9F%98%80.png"
+        );
+    }
+
+    #[test]
+    fn content_disposition_escapes_quotes() {
+        assert_eq!(
+            content_disposition("say \"hi\"\\.txt"),
+            "attachment; filename=\"say _hi__.txt\"; filename*=UTF-8''say%20%22hi%22%5C.txt"
+        );
+    }
+
+    #[test]
+    fn percent_encode_keeps_attr_chars() {
+        let attr_chars = "azAZ09!#$&+-.^_`|~";
+        assert_eq!(percent_encode(attr_chars), attr_chars);
+        assert_eq!(
+            percent_encode(" %'()*,/:;=?@[]{}"),
+            "%20%25%27%28%29%2A%2C%2F%3A%3B%3D%3F%40%5B%5D%7B%7D"
+        );
+    }
+}
@@ -1,27 +1,86 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use anyhow::Context;
+use chrono::{DateTime, Utc};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{Connection, OptionalExtension};
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::{config::Config, share::SharedIndex};
+
+/// Most connections open at once. WAL mode lets them read in parallel,
+/// writes still take turns.
+const POOL_SIZE: u32 = 8;
+
+/// How long a connection waits for another one to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Columns [`record_from_row`] reads, in order
+const RECORD_COLUMNS: &str =
+    "uuid, uploaded_at, name, description, author, sha256, size, stored_size, encoding, encrypted, e2e";
+
+fn record_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Record> {
+    Ok(Record {
+        uuid: Uuid::parse_str(&row.get::<_, String>(0)?).unwrap(),
+        uploaded_at: row.get(1)?,
+        name: row.get(2)?,
+        description: row.get(3)?,
+        author: row.get(4)?,
+        sha256: row.get(5)?,
+        // SQLite integers are signed
+        size: row.get::<_, Option<i64>>(6)?.map(|n| n as u64),
+        stored_size: row.get::<_, Option<i64>>(7)?.map(|n| n as u64),
+        encoding: row.get(8)?,
+        encrypted: row.get(9)?,
+        e2e: row.get(10)?,
+    })
+}
+
+/// Returned when adding or removing files while a directory is shared
+#[derive(Debug)]
+pub struct ReadOnly;
+
+impl std::fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileManager: read-only, a directory is shared")
+    }
+}
+
+impl std::error::Error for ReadOnly {}
 
 // Manages files
+// Queries block, async code goes through [`AsyncFileManager`].
 pub struct FileManager {
     // working directory
     working_dir: PathBuf,
-    // database connection
-    conn: Connection,
-    #[allow(dead_code)]
-    conf: Config,
+    // database connections
+    pool: Pool<SqliteConnectionManager>,
+    // directory served in place, records come from its index instead of the database
+    shared: Option<(PathBuf, RwLock<SharedIndex>)>,
 }
 use record::Record;
 impl FileManager {
     // Create new instance and connect to db.
     pub fn new<P: AsRef<Path>>(working_dir: P, config: Config) -> anyhow::Result<Self> {
         let path = working_dir.as_ref();
-        let conn = Connection::open(path.join(&config.path.db))
+        let manager = SqliteConnectionManager::file(path.join(&config.path.db)).with_init(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            // readers no longer wait for writers, and writers only for each other
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "synchronous", "NORMAL")
+        });
+        let pool = Pool::builder()
+            .max_size(POOL_SIZE)
+            // command line tools need a single connection
+            .min_idle(Some(1))
+            .build(manager)
             .context("FileManager: database connection failed")?;
+        let conn = pool.get().context("FileManager: database connection failed")?;
         conn.execute(
             r#"
             CREATE TABLE IF NOT EXISTS records (
@@ -36,27 +95,73 @@ impl FileManager {
             [],
         )
         .context("FileManager: SQL execution failed")?;
+        migrate(&conn).context("FileManager: schema migration failed")?;
+        drop(conn);
         Ok(Self {
             working_dir: working_dir.as_ref().into(),
-            conn,
-            conf: config,
+            pool,
+            shared: None,
         })
     }
 
-    pub fn get_all_records(&mut self) -> anyhow::Result<Vec<Record>> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT uuid, uploaded_at, name, description, author FROM records")
+    fn conn(&self) -> anyhow::Result<PooledConnection<SqliteConnectionManager>> {
+        self.pool
+            .get()
+            .context("FileManager: no database connection available")
+    }
+
+    /// Serves `root` in place instead of the uploaded files, read-only.
+    /// Indexes the directory, which blocks until every file has been seen.
+    pub fn share(&mut self, root: PathBuf) -> anyhow::Result<()> {
+        if !root.is_dir() {
+            anyhow::bail!("Shared directory {} does not exist", root.display());
+        }
+        let index = SharedIndex::scan(&root)?;
+        log::info!("share: serving {} file(s) from {}", index.len(), root.display());
+        self.shared = Some((root, RwLock::new(index)));
+        Ok(())
+    }
+
+    pub fn shared_dir(&self) -> Option<&Path> {
+        self.shared.as_ref().map(|(root, _)| root.as_path())
+    }
+
+    /// Swaps in a fresh index of the shared directory,
+    /// returns the number of files added and removed
+    pub fn replace_shared_index(&self, index: SharedIndex) -> (usize, usize) {
+        match &self.shared {
+            Some((_, current)) => {
+                let mut current = current.write().unwrap();
+                let diff = index.diff(&current);
+                *current = index;
+                diff
+            }
+            None => (0, 0),
+        }
+    }
+
+    /// Whether files can be added or removed
+    pub fn is_read_only(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    /// Key the content of a file is stored under, see [`crate::storage`]
+    pub fn blob_key(&self, uuid: Uuid) -> Option<String> {
+        match &self.shared {
+            Some((_, index)) => index.read().unwrap().key(uuid).map(str::to_string),
+            None => Some(uuid.to_string()),
+        }
+    }
+
+    pub fn get_all_records(&self) -> anyhow::Result<Vec<Record>> {
+        if let Some((_, index)) = &self.shared {
+            return Ok(index.read().unwrap().records());
+        }
+        let conn = self.conn()?;
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM records WHERE pending = 0", RECORD_COLUMNS))
             .context("Sql prepare failed")?;
-        let rows = stmt.query_map([], |row| {
-            Ok(Record {
-                uuid: uuid::Uuid::parse_str(&row.get::<_, String>(0)?).unwrap(),
-                uploaded_at: row.get(1)?,
-                name: row.get(2)?,
-                description: row.get(3)?,
-                author: row.get(4)?,
-            })
-        })?;
+        let rows = stmt.query_map([], record_from_row)?;
         let mut records = Vec::new();
         for r in rows {
             records.push(r?);
@@ -65,70 +170,302 @@ impl FileManager {
     }
 
     pub fn get_record_by_uuid(&self, uuid: Uuid) -> anyhow::Result<Option<Record>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT uuid, uploaded_at, name, description, author FROM records WHERE uuid = ?1",
-        )?;
+        if let Some((_, index)) = &self.shared {
+            return Ok(index.read().unwrap().get(uuid).cloned());
+        }
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare(&format!(
+            "SELECT {} FROM records WHERE uuid = ?1 AND pending = 0",
+            RECORD_COLUMNS
+        ))?;
         let record = stmt
-            .query_row([uuid.to_string()], |row| {
-                Ok(Record {
-                    uuid: Uuid::parse_str(&row.get::<_, String>(0)?).unwrap(),
-                    uploaded_at: row.get(1)?,
-                    name: row.get(2)?,
-                    description: row.get(3)?,
-                    author: row.get(4)?,
-                })
-            })
+            .query_row([uuid.to_string()], record_from_row)
             .optional()?; // May not return a row
 
         Ok(record)
     }
 
-    pub fn insert_record(&mut self, record: Record) -> anyhow::Result<i64> {
-        let res = self
-            .conn
+    pub fn insert_record(&self, record: Record) -> anyhow::Result<i64> {
+        self.insert(record, false)
+    }
+
+    /// Adds a record that no query returns until [`FileManager::commit_record`].
+    /// For files recorded before their content is stored, so a crash in between
+    /// leaves a record [`FileManager::pending_records`] finds on the next start.
+    pub fn insert_pending_record(&self, record: Record) -> anyhow::Result<i64> {
+        self.insert(record, true)
+    }
+
+    fn insert(&self, record: Record, pending: bool) -> anyhow::Result<i64> {
+        if self.is_read_only() {
+            return Err(ReadOnly.into());
+        }
+        let conn = self.conn()?;
+        let res = conn
             .execute(
                 r#"
-            INSERT INTO records (uuid, uploaded_at, name, description, author)
-            VALUES (?1, ?2, ?3, ?4, ?5)
+            INSERT INTO records (uuid, uploaded_at, name, description, author, sha256,
+                                 size, stored_size, encoding, encrypted, e2e, pending)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
             "#,
                 rusqlite::params![
                     record.uuid.to_string(),
                     record.uploaded_at,
                     record.name,
                     record.description,
-                    record.author
+                    record.author,
+                    record.sha256,
+                    record.size.map(|n| n as i64),
+                    record.stored_size.map(|n| n as i64),
+                    record.encoding,
+                    record.encrypted,
+                    record.e2e,
+                    pending
                 ],
             )
             .context("FileManager: SQL insertion failed")?;
         log::info!("SQL: insert_record: {} rows affected", res);
-        Ok(self.conn.last_insert_rowid())
+        Ok(conn.last_insert_rowid())
     }
 
-    pub fn delete_record(&mut self, uuid: Uuid) -> anyhow::Result<bool> {
-        let rows = self.conn.execute("DELETE FROM records WHERE uuid = ?1", [uuid.to_string()])?;
+    /// Makes a pending record visible, its content is stored
+    pub fn commit_record(&self, uuid: Uuid) -> anyhow::Result<bool> {
+        let rows = self.conn()?.execute(
+            "UPDATE records SET pending = 0 WHERE uuid = ?1",
+            [uuid.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+    /// Records that were never committed, see [`FileManager::insert_pending_record`]
+    pub fn pending_records(&self) -> anyhow::Result<Vec<Uuid>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT uuid FROM records WHERE pending = 1")?;
+        let uuids = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|uuid| uuid.map(|u| Uuid::parse_str(&u).ok()).transpose())
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(uuids)
+    }
+    pub fn delete_record(&self, uuid: Uuid) -> anyhow::Result<bool> {
+        if self.is_read_only() {
+            return Err(ReadOnly.into());
+        }
+        let rows = self.conn()?.execute("DELETE FROM records WHERE uuid = ?1", [uuid.to_string()])?;
+        Ok(rows > 0)
+    }
+    pub fn set_checksum(&self, uuid: Uuid, sha256: &str) -> anyhow::Result<bool> {
+        let rows = self.conn()?.execute(
+            "UPDATE records SET sha256 = ?1 WHERE uuid = ?2",
+            [sha256, &uuid.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+    /// Records the content as stored in plain, neither compressed nor encrypted, `size` bytes long
+    pub fn set_stored_plain(&self, uuid: Uuid, size: u64) -> anyhow::Result<bool> {
+        self.set_stored(uuid, size, size, None, false)
+    }
+    /// Records how the content is stored: `size` bytes of content, `stored_size` on disk
+    pub fn set_stored(
+        &self,
+        uuid: Uuid,
+        size: u64,
+        stored_size: u64,
+        encoding: Option<&str>,
+        encrypted: bool,
+    ) -> anyhow::Result<bool> {
+        let rows = self.conn()?.execute(
+            "UPDATE records SET size = ?1, stored_size = ?2, encoding = ?3, encrypted = ?4 \
+             WHERE uuid = ?5",
+            rusqlite::params![size as i64, stored_size as i64, encoding, encrypted, uuid.to_string()],
+        )?;
         Ok(rows > 0)
     }
+    pub fn set_uploaded_at(&self, uuid: Uuid, uploaded_at: DateTime<Utc>) -> anyhow::Result<bool> {
+        let rows = self.conn()?.execute(
+            "UPDATE records SET uploaded_at = ?1 WHERE uuid = ?2",
+            rusqlite::params![uploaded_at, uuid.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+    /// Remembers where an imported file came from, see [`crate::import`]
+    pub fn set_source(&self, uuid: Uuid, source: &str) -> anyhow::Result<bool> {
+        let rows = self.conn()?.execute(
+            "UPDATE records SET source = ?1 WHERE uuid = ?2",
+            [source, &uuid.to_string()],
+        )?;
+        Ok(rows > 0)
+    }
+    pub fn get_record_by_source(&self, source: &str) -> anyhow::Result<Option<Record>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare(&format!(
+            "SELECT {} FROM records WHERE source = ?1 AND pending = 0",
+            RECORD_COLUMNS
+        ))?;
+        let record = stmt.query_row([source], record_from_row).optional()?;
+        Ok(record)
+    }
+    /// Salt of the passphrase and check value of the master key, see [`crate::crypto`]
+    pub fn encryption_params(&self) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let params = self
+            .conn()?
+            .query_row("SELECT salt, key_check FROM encryption", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        Ok(params)
+    }
+    pub fn set_encryption_params(&self, salt: &[u8], key_check: &[u8]) -> anyhow::Result<()> {
+        self.conn()?.execute(
+            "INSERT OR REPLACE INTO encryption (id, salt, key_check) VALUES (1, ?1, ?2)",
+            [salt, key_check],
+        )?;
+        Ok(())
+    }
     pub fn get_wd(&self) -> &Path {
         &self.working_dir
     }
 }
 
+/// Async access to a [`FileManager`].
+/// Queries run on the blocking thread pool, so a slow disk never stalls the
+/// runtime threads streaming uploads and downloads.
+#[derive(Clone)]
+pub struct AsyncFileManager(Arc<FileManager>);
+
+impl AsyncFileManager {
+    pub fn new(fm: FileManager) -> Self {
+        Self(Arc::new(fm))
+    }
+
+    /// Runs blocking work with the file manager.
+    /// Runs to the end even when the returned future is dropped.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&FileManager) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let fm = Arc::clone(&self.0);
+        tokio::task::spawn_blocking(move || work(&fm))
+            .await
+            .context("FileManager: database task failed")?
+    }
+
+    pub async fn get_all_records(&self) -> anyhow::Result<Vec<Record>> {
+        self.run(|fm| fm.get_all_records()).await
+    }
+
+    pub async fn get_record_by_uuid(&self, uuid: Uuid) -> anyhow::Result<Option<Record>> {
+        self.run(move |fm| fm.get_record_by_uuid(uuid)).await
+    }
+
+    pub async fn insert_record(&self, record: Record) -> anyhow::Result<i64> {
+        self.run(move |fm| fm.insert_record(record)).await
+    }
+
+    pub async fn insert_pending_record(&self, record: Record) -> anyhow::Result<i64> {
+        self.run(move |fm| fm.insert_pending_record(record)).await
+    }
+
+    pub async fn commit_record(&self, uuid: Uuid) -> anyhow::Result<bool> {
+        self.run(move |fm| fm.commit_record(uuid)).await
+    }
+
+    pub async fn pending_records(&self) -> anyhow::Result<Vec<Uuid>> {
+        self.run(|fm| fm.pending_records()).await
+    }
+
+    pub async fn delete_record(&self, uuid: Uuid) -> anyhow::Result<bool> {
+        self.run(move |fm| fm.delete_record(uuid)).await
+    }
+
+    // the rest is answered from memory and does not block
+
+    pub fn is_read_only(&self) -> bool {
+        self.0.is_read_only()
+    }
+
+    pub fn blob_key(&self, uuid: Uuid) -> Option<String> {
+        self.0.blob_key(uuid)
+    }
+
+    pub fn shared_dir(&self) -> Option<&Path> {
+        self.0.shared_dir()
+    }
+
+    pub fn replace_shared_index(&self, index: SharedIndex) -> (usize, usize) {
+        self.0.replace_shared_index(index)
+    }
+}
+
+/// Brings databases created by older versions up to the current schema
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('records')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if !columns.iter().any(|c| c == "sha256") {
+        conn.execute("ALTER TABLE records ADD COLUMN sha256 TEXT", [])?;
+    }
+    // path a file was imported from, NULL for uploads
+    if !columns.iter().any(|c| c == "source") {
+        conn.execute("ALTER TABLE records ADD COLUMN source TEXT", [])?;
+    }
+    // sizes in bytes and the encoding of compressed files, NULL for older files
+    for (column, kind) in [
+        ("size", "INTEGER"),
+        ("stored_size", "INTEGER"),
+        ("encoding", "TEXT"),
+        ("encrypted", "INTEGER NOT NULL DEFAULT 0"),
+        ("e2e", "INTEGER NOT NULL DEFAULT 0"),
+        // recorded upload whose content is not stored yet
+        ("pending", "INTEGER NOT NULL DEFAULT 0"),
+    ] {
+        if !columns.iter().any(|c| c == column) {
+            conn.execute(&format!("ALTER TABLE records ADD COLUMN {} {}", column, kind), [])?;
+        }
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS encryption (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            salt BLOB NOT NULL,
+            key_check BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
 pub mod record {
     use chrono::{DateTime, Utc};
+    use rocket_okapi::JsonSchema;
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
     // Record type to
-    #[derive(Debug, Serialize, Deserialize, Clone)]
+    #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
     pub struct Record {
-        // UUID for file paths
+        /// UUID for file paths
         pub uuid: Uuid,
         pub uploaded_at: DateTime<Utc>,
-        // file name for display
+        /// file name for display
         pub name: String,
-        // description
+        /// description
         pub description: Option<String>,
-        // who uploaded
+        /// who uploaded
         pub author: String,
+        /// hex encoded SHA-256 of the content, missing for files uploaded by older versions
+        pub sha256: Option<String>,
+        /// size of the content in bytes, missing for files uploaded by older versions
+        pub size: Option<u64>,
+        /// bytes taken in the storage, less than `size` when compressed
+        pub stored_size: Option<u64>,
+        /// `zstd` when stored compressed, see [`crate::compression`]
+        pub encoding: Option<String>,
+        /// stored encrypted, see [`crate::crypto`]
+        pub encrypted: bool,
+        /// encrypted by the browser before upload, the server only ever sees the
+        /// ciphertext and the key stays in the share link
+        pub e2e: bool,
     }
 }
@@ -0,0 +1,323 @@
+//! This module checks that `uploads/` and the records table agree, and repairs them
+//!
+//! Run it while the server is stopped, uploads in progress look like orphan blobs.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    compression,
+    config::{self, Config, EncryptionConfig},
+    crypto::{self, MasterKey},
+    fm::{FileManager, record::Record},
+    utils,
+};
+
+/// What `--repair` does with the problems found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Delete orphan blobs, dangling records and corrupted files
+    Delete,
+    /// Create records for orphan blobs and accept the current content of corrupted files
+    Reimport,
+    /// Move orphan blobs and corrupted files to `quarantine/`
+    Quarantine,
+}
+
+impl std::str::FromStr for Repair {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delete" => Ok(Self::Delete),
+            "reimport" => Ok(Self::Reimport),
+            "quarantine" => Ok(Self::Quarantine),
+            other => anyhow::bail!(
+                "unknown repair action '{}', expected delete, reimport or quarantine",
+                other
+            ),
+        }
+    }
+}
+
+/// A checksum that no longer matches the stored content
+#[derive(Debug)]
+pub struct Mismatch {
+    pub record: Record,
+    /// `None` when compressed or encrypted content can not be read
+    pub actual: Option<String>,
+}
+
+/// Problems found by a check
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Files in `uploads/` without a record
+    pub orphan_blobs: Vec<PathBuf>,
+    /// Records whose file is missing from `uploads/`
+    pub dangling_records: Vec<Record>,
+    /// Records whose file content does not match the stored checksum
+    pub checksum_mismatches: Vec<Mismatch>,
+    /// Records uploaded before checksums were stored, with their computed checksum
+    pub missing_checksums: Vec<(Record, String)>,
+    /// Encrypted records whose content was not checked, no key was given
+    pub unverified: Vec<Record>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_blobs.is_empty()
+            && self.dangling_records.is_empty()
+            && self.checksum_mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.orphan_blobs {
+            writeln!(f, "orphan blob: {}", path.display())?;
+        }
+        for record in &self.dangling_records {
+            writeln!(f, "dangling record: {} ({})", record.uuid, record.name)?;
+        }
+        for mismatch in &self.checksum_mismatches {
+            writeln!(
+                f,
+                "checksum mismatch: {} ({}): expected {}, found {}",
+                mismatch.record.uuid,
+                mismatch.record.name,
+                mismatch.record.sha256.as_deref().unwrap_or_default(),
+                mismatch.actual.as_deref().unwrap_or("unreadable content")
+            )?;
+        }
+        if !self.unverified.is_empty() {
+            writeln!(
+                f,
+                "{} encrypted file(s) not verified, no key was given",
+                self.unverified.len()
+            )?;
+        }
+        if !self.missing_checksums.is_empty() {
+            writeln!(
+                f,
+                "{} record(s) without stored checksum",
+                self.missing_checksums.len()
+            )?;
+        }
+        write!(
+            f,
+            "{} orphan blob(s), {} dangling record(s), {} checksum mismatch(es)",
+            self.orphan_blobs.len(),
+            self.dangling_records.len(),
+            self.checksum_mismatches.len()
+        )
+    }
+}
+
+/// Compares `uploads/` with the records table
+pub fn check(workdir: &Path, conf: &Config) -> anyhow::Result<Report> {
+    let fm = FileManager::new(workdir, conf.clone())?;
+    // without a key everything but encrypted content is checked
+    let key = crypto::verify_key(
+        workdir,
+        &EncryptionConfig {
+            enabled: false,
+            ..conf.encryption.clone()
+        },
+        &fm,
+    )?;
+    let uploads_dir = workdir.join(&conf.path.uploads);
+    let mut records: HashMap<Uuid, Record> = fm
+        .get_all_records()?
+        .into_iter()
+        .map(|r| (r.uuid, r))
+        .collect();
+
+    let mut report = Report::default();
+    let entries = std::fs::read_dir(&uploads_dir)
+        .context(format!("Could not read {}", uploads_dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let record = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<Uuid>().ok())
+            .and_then(|uuid| records.remove(&uuid));
+        let Some(record) = record else {
+            report.orphan_blobs.push(path);
+            continue;
+        };
+        let actual = match content_sha256(&path, &record, key.as_ref()) {
+            Ok(Some(actual)) => actual,
+            Ok(None) => {
+                report.unverified.push(record);
+                continue;
+            }
+            Err(_) if record.encrypted || record.encoding.is_some() => {
+                report.checksum_mismatches.push(Mismatch { record, actual: None });
+                continue;
+            }
+            Err(e) => return Err(e).context(format!("Could not read {}", path.display())),
+        };
+        match &record.sha256 {
+            Some(expected) if expected.eq_ignore_ascii_case(&actual) => {}
+            Some(_) => report.checksum_mismatches.push(Mismatch {
+                record,
+                actual: Some(actual),
+            }),
+            None => report.missing_checksums.push((record, actual)),
+        }
+    }
+    // records left over have no file
+    report.dangling_records = records.into_values().collect();
+    report.dangling_records.sort_by_key(|r| r.uploaded_at);
+    Ok(report)
+}
+
+/// SHA-256 of the original content of a stored file,
+/// `None` when it is encrypted and there is no key
+fn content_sha256(path: &Path, record: &Record, key: Option<&MasterKey>) -> io::Result<Option<String>> {
+    let stored: Box<dyn Read> = match (record.encrypted, key) {
+        (true, Some(key)) => Box::new(crypto::FileDecryptor::open(path, key)?),
+        (true, None) => return Ok(None),
+        (false, _) => Box::new(std::fs::File::open(path)?),
+    };
+    let content: Box<dyn Read> = match record.encoding.as_deref() {
+        Some(compression::ZSTD) => Box::new(zstd::Decoder::new(stored)?),
+        _ => stored,
+    };
+    utils::sha256_reader(content).map(Some)
+}
+
+/// Fixes the problems in `report` and returns a line for each action taken
+pub fn repair(
+    workdir: &Path,
+    conf: &Config,
+    report: Report,
+    action: Repair,
+) -> anyhow::Result<Vec<String>> {
+    let fm = FileManager::new(workdir, conf.clone())?;
+    let uploads_dir = workdir.join(&conf.path.uploads);
+    let quarantine_dir = workdir.join(config::QUARANTINE_DIR);
+    let mut done = Vec::new();
+
+    let quarantine = |path: &Path| -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(&quarantine_dir)?;
+        let target = quarantine_dir.join(path.file_name().unwrap_or_default());
+        std::fs::rename(path, &target)?;
+        Ok(target)
+    };
+
+    for path in report.orphan_blobs {
+        match action {
+            Repair::Delete => {
+                std::fs::remove_file(&path)?;
+                done.push(format!("deleted orphan blob {}", path.display()));
+            }
+            Repair::Quarantine => {
+                let target = quarantine(&path)?;
+                done.push(format!(
+                    "quarantined orphan blob {} to {}",
+                    path.display(),
+                    target.display()
+                ));
+            }
+            Repair::Reimport => {
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let size = std::fs::metadata(&path)?.len();
+                let record = Record {
+                    uuid: Uuid::new_v4(),
+                    uploaded_at: Utc::now(),
+                    name,
+                    description: Some("Recovered by localshare fsck".to_string()),
+                    author: "localshare fsck".to_string(),
+                    sha256: Some(utils::sha256_file(&path)?),
+                    size: Some(size),
+                    stored_size: Some(size),
+                    encoding: None,
+                    encrypted: false,
+                    e2e: false,
+                };
+                let target = uploads_dir.join(record.uuid.to_string());
+                std::fs::rename(&path, &target)?;
+                let uuid = record.uuid;
+                if let Err(e) = fm.insert_record(record) {
+                    // put the blob back where it was found
+                    std::fs::rename(&target, &path)?;
+                    return Err(e);
+                }
+                done.push(format!(
+                    "reimported orphan blob {} as {}",
+                    path.display(),
+                    uuid
+                ));
+            }
+        }
+    }
+
+    for record in report.dangling_records {
+        fm.delete_record(record.uuid)?;
+        done.push(format!(
+            "removed dangling record {} ({})",
+            record.uuid, record.name
+        ));
+    }
+
+    for mismatch in report.checksum_mismatches {
+        let uuid = mismatch.record.uuid;
+        let path = uploads_dir.join(uuid.to_string());
+        match action {
+            Repair::Delete => {
+                std::fs::remove_file(&path)?;
+                fm.delete_record(uuid)?;
+                done.push(format!("deleted corrupted file {}", uuid));
+            }
+            Repair::Quarantine => {
+                let target = quarantine(&path)?;
+                fm.delete_record(uuid)?;
+                done.push(format!(
+                    "quarantined corrupted file {} to {}",
+                    uuid,
+                    target.display()
+                ));
+            }
+            Repair::Reimport => {
+                // undecodable content is accepted as it is stored, unless it is encrypted
+                let actual = match mismatch.actual {
+                    Some(actual) => actual,
+                    None if mismatch.record.encrypted => {
+                        done.push(format!(
+                            "kept unreadable encrypted file {}, quarantine or delete it instead",
+                            uuid
+                        ));
+                        continue;
+                    }
+                    None => {
+                        fm.set_stored_plain(uuid, std::fs::metadata(&path)?.len())?;
+                        utils::sha256_file(&path)?
+                    }
+                };
+                fm.set_checksum(uuid, &actual)?;
+                done.push(format!("accepted current content of {} as {}", uuid, actual));
+            }
+        }
+    }
+
+    for (record, sha256) in report.missing_checksums {
+        fm.set_checksum(record.uuid, &sha256)?;
+        done.push(format!("stored checksum of {}", record.uuid));
+    }
+    Ok(done)
+}
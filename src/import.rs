@@ -0,0 +1,222 @@
+//! This module imports files that already exist on disk into a server directory
+//!
+//! Every imported record remembers the path it came from, so running an import
+//! again only picks up files that are new or have changed since.
+
+use std::{
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::{
+    compression,
+    config::{CompressionConfig, Config},
+    crypto::{self, MasterKey},
+    fm::{FileManager, record::Record},
+    utils,
+};
+
+/// How files get into `uploads/`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Copy,
+    /// Hard link, falling back to a copy across filesystems
+    Link,
+}
+
+/// Outcome of an import
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub imported: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    /// One line for every file imported, updated or skipped
+    pub files: Vec<String>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} imported, {} updated, {} unchanged, {} skipped",
+            self.imported, self.updated, self.unchanged, self.skipped
+        )
+    }
+}
+
+/// Where imported content goes and how it is stored, the same way uploads are
+struct Store<'a> {
+    uploads_dir: PathBuf,
+    mode: Mode,
+    compression: &'a CompressionConfig,
+    key: Option<MasterKey>,
+    /// Drives the async writers that compress and encrypt
+    runtime: tokio::runtime::Runtime,
+}
+
+/// Imports every regular file below `source`.
+/// Symbolic links are skipped, as is the server directory if it lies inside `source`.
+/// Files are compressed and encrypted as uploads are, and then written rather than linked.
+/// Blocking, must not be called from async code.
+pub fn run(
+    workdir: &Path,
+    conf: &Config,
+    source: &Path,
+    author: &str,
+    mode: Mode,
+) -> anyhow::Result<Summary> {
+    let fm = FileManager::new(workdir, conf.clone())?;
+    let key = crypto::load_key(workdir, &conf.encryption, &fm)?
+        .filter(|_| conf.encryption.enabled);
+    let store = Store {
+        uploads_dir: workdir.join(&conf.path.uploads),
+        mode,
+        compression: &conf.storage.compression,
+        key,
+        runtime: tokio::runtime::Builder::new_current_thread().build()?,
+    };
+    let source = source
+        .canonicalize()
+        .context(format!("Could not open {}", source.display()))?;
+    let skip_dir = workdir.canonicalize()?;
+
+    let mut summary = Summary::default();
+    let mut pending = vec![source];
+    while let Some(path) = pending.pop() {
+        let meta = std::fs::symlink_metadata(&path)
+            .context(format!("Could not read {}", path.display()))?;
+        if meta.is_dir() {
+            if path == skip_dir {
+                continue;
+            }
+            let mut entries = std::fs::read_dir(&path)
+                .context(format!("Could not read {}", path.display()))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<PathBuf>>>()?;
+            // popped from the back, so this walks in name order
+            entries.sort_by(|a, b| b.cmp(a));
+            pending.extend(entries);
+        } else if meta.is_file() {
+            import_file(&fm, &store, &path, author, &mut summary)
+                .context(format!("Could not import {}", path.display()))?;
+        } else {
+            summary
+                .files
+                .push(format!("skipped {}: not a regular file", path.display()));
+            summary.skipped += 1;
+        }
+    }
+    Ok(summary)
+}
+
+fn import_file(
+    fm: &FileManager,
+    store: &Store,
+    path: &Path,
+    author: &str,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let source = path.to_string_lossy();
+    let sha256 = utils::sha256_file(path)?;
+    let meta = std::fs::metadata(path)?;
+    let modified: DateTime<Utc> = meta.modified()?.into();
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let level = compression::level_for(store.compression, &name);
+    let encoding = level.map(|_| compression::ZSTD);
+    let encrypted = store.key.is_some();
+
+    if let Some(existing) = fm.get_record_by_source(&source)? {
+        let blob = store.uploads_dir.join(existing.uuid.to_string());
+        if existing.sha256.as_deref() == Some(sha256.as_str()) && blob.exists() {
+            summary.unchanged += 1;
+            return Ok(());
+        }
+        let stored_size = place(store, path, &blob, level)?;
+        fm.set_checksum(existing.uuid, &sha256)?;
+        fm.set_stored(existing.uuid, meta.len(), stored_size, encoding, encrypted)?;
+        fm.set_uploaded_at(existing.uuid, modified)?;
+        summary.files.push(format!("updated {}", path.display()));
+        summary.updated += 1;
+        return Ok(());
+    }
+
+    let uuid = Uuid::new_v4();
+    let blob = store.uploads_dir.join(uuid.to_string());
+    let stored_size = place(store, path, &blob, level)?;
+    let record = Record {
+        uuid,
+        uploaded_at: modified,
+        name,
+        description: None,
+        author: author.to_string(),
+        sha256: Some(sha256),
+        size: Some(meta.len()),
+        stored_size: Some(stored_size),
+        encoding: encoding.map(str::to_string),
+        encrypted,
+        e2e: false,
+    };
+    let inserted = fm
+        .insert_record(record)
+        .and_then(|_| fm.set_source(uuid, &source));
+    if let Err(e) = inserted {
+        fm.delete_record(uuid).ok();
+        std::fs::remove_file(&blob).ok();
+        return Err(e);
+    }
+    summary.files.push(format!("imported {}", path.display()));
+    summary.imported += 1;
+    Ok(())
+}
+
+/// Puts the content of `from` at `to`, compressed at `level` if set and encrypted
+/// if the store has a key, keeping its modification time. Returns the stored size.
+fn place(store: &Store, from: &Path, to: &Path, level: Option<i32>) -> anyhow::Result<u64> {
+    if to.exists() {
+        std::fs::remove_file(to)?;
+    }
+    let plain = level.is_none() && store.key.is_none();
+    if plain && store.mode == Mode::Link {
+        match std::fs::hard_link(from, to) {
+            Ok(()) => return Ok(std::fs::metadata(to)?.len()),
+            Err(e) => log::warn!("import: could not link {}, copying: {}", from.display(), e),
+        }
+    }
+    if plain {
+        std::fs::copy(from, to)?;
+    } else {
+        store.runtime.block_on(encode(from, to, store.key.as_ref(), level))?;
+    }
+    let modified: SystemTime = std::fs::metadata(from)?.modified()?;
+    let file = File::options().write(true).open(to)?;
+    file.set_modified(modified)?;
+    Ok(file.metadata()?.len())
+}
+
+/// Writes `from` to `to` through the writers uploads go through
+async fn encode(
+    from: &Path,
+    to: &Path,
+    key: Option<&MasterKey>,
+    level: Option<i32>,
+) -> std::io::Result<()> {
+    let mut content = tokio::fs::File::open(from).await?;
+    let file = tokio::fs::File::create(to).await?;
+    let stored = file.try_clone().await?;
+    let mut writer = compression::writer(crypto::writer(file, key), level);
+    tokio::io::copy(&mut content, &mut writer).await?;
+    // ends the compressed stream and seals the last chunk
+    writer.shutdown().await?;
+    stored.sync_all().await
+}
@@ -7,3 +7,20 @@ pub mod qr;
 pub mod mdns;
 pub mod utils;
 pub mod session;
+pub mod listener;
+pub mod reload;
+pub mod transfer;
+pub mod fsck;
+pub mod import;
+pub mod archive;
+pub mod share;
+pub mod digest;
+pub mod filename;
+pub mod error;
+pub mod storage;
+pub mod range;
+pub mod compression;
+pub mod crypto;
+pub mod client;
+pub mod ratelimit;
+pub mod access;
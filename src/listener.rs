@@ -0,0 +1,63 @@
+//! This module serves listeners that Rocket can not bind by itself
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use tokio::sync::oneshot;
+
+/// Accepts connections on a unix domain socket and forwards each of them
+/// to the TCP listener whose address arrives through `target` once it is bound.
+/// Runs until the task is aborted.
+#[cfg(unix)]
+pub async fn forward_unix_socket(path: PathBuf, target: oneshot::Receiver<SocketAddr>) {
+    use tokio::net::{TcpStream, UnixListener};
+
+    let Ok(target) = target.await else {
+        log::error!("unix socket: the internal listener never started");
+        return;
+    };
+
+    // A socket file left behind by a previous run would make bind fail
+    if path.exists()
+        && let Err(e) = std::fs::remove_file(&path)
+    {
+        log::error!("unix socket: could not remove stale socket {}: {}", path.display(), e);
+        return;
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("unix socket: could not bind {}: {}", path.display(), e);
+            return;
+        }
+    };
+    log::info!("unix socket: listening on {}", path.display());
+    loop {
+        let mut incoming = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::warn!("unix socket: accept failed: {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let mut outgoing = match TcpStream::connect(target).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!("unix socket: could not reach listener at {}: {}", target, e);
+                    return;
+                }
+            };
+            if let Err(e) = tokio::io::copy_bidirectional(&mut incoming, &mut outgoing).await {
+                log::debug!("unix socket: connection closed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn forward_unix_socket(path: PathBuf, _target: oneshot::Receiver<SocketAddr>) {
+    log::error!(
+        "unix socket: {} requested, but unix sockets are not supported on this platform",
+        path.display()
+    );
+}
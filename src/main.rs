@@ -1,18 +1,19 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use anyhow::Context;
-use localshare::{assets, config::Config, qr, server::Server, mdns};
+use localshare::{archive, assets, cli, config::{self, Config, Overrides}, fsck, import, qr, server::Server, mdns};
 use tokio::fs;
 
 #[rocket::main]
 async fn main() -> anyhow::Result<()> {
     init_logger()?;
-    let matches = localshare::cli::get_command().get_matches();
+    let matches = cli::get_command().get_matches();
 
     match matches.subcommand().expect("subcommand required") {
         ("run", m) => {
-            let path = m.get_one("workdir").expect("workdir is required argument");
-            handle_run(path)
+            let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+            handle_run(path, cli::overrides(m))
                 .await
                 .context("Failed to start the server")?;
         }
@@ -23,6 +24,55 @@ async fn main() -> anyhow::Result<()> {
                 .await
                 .context("Failed to initialise server directory")?;
         }
+        ("config", m) => match m.subcommand().expect("subcommand required") {
+            ("show", m) => {
+                let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+                handle_config_show(path).await?;
+            }
+            ("check", m) => {
+                let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+                handle_config_check(path).await?;
+            }
+            _ => {
+                unreachable!("no other config subcmd");
+            }
+        },
+        ("import", m) => {
+            let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+            let source = m.get_one::<PathBuf>("path").expect("path is required argument");
+            let author = m.get_one::<String>("author").expect("author has a default");
+            let mode = if m.get_flag("link") {
+                import::Mode::Link
+            } else {
+                import::Mode::Copy
+            };
+            handle_import(path, source, author, mode)
+                .await
+                .context("Import failed")?;
+        }
+        ("export", m) => {
+            let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+            let archive = m.get_one::<PathBuf>("archive").expect("archive is required argument");
+            let base = m.get_one::<PathBuf>("base");
+            handle_export(path, archive, base.map(PathBuf::as_path))
+                .await
+                .context("Export failed")?;
+        }
+        ("restore", m) => {
+            let archive = m.get_one::<PathBuf>("archive").expect("archive is required argument");
+            let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+            handle_restore(archive, path)
+                .await
+                .context("Restore failed")?;
+        }
+        ("fsck", m) => {
+            let path = m.get_one::<PathBuf>("workdir").expect("workdir is required argument");
+            let repair = m
+                .get_one::<String>("repair")
+                .map(|s| s.parse::<fsck::Repair>())
+                .transpose()?;
+            handle_fsck(path, repair).await?;
+        }
         _ => {
             unreachable!("no other subcmd");
         }
@@ -65,8 +115,8 @@ async fn handle_new(path: &PathBuf, auth: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn handle_run(path: &PathBuf) -> anyhow::Result<()> {
-    let conf = Config::read_path(path)
+async fn handle_run(path: &Path, overrides: Overrides) -> anyhow::Result<()> {
+    let conf = Config::load(path, overrides.clone())
         .await
         .context(format!(
             "Failed to read configuration from '{}'. \
@@ -76,13 +126,150 @@ async fn handle_run(path: &PathBuf) -> anyhow::Result<()> {
 
 
 
-    let mut mdns_service = mdns::start_service(&conf)
+    let mdns_service = mdns::start_service(&conf)
         .context("Could not start mDNS service")?;
+    let mdns_service = Arc::new(Mutex::new(mdns_service));
     qr::generate_qr(path, &conf);
+    qr::print_qr(&conf);
+    tokio::spawn(qr::refresh_on_network_change(path.to_path_buf(), conf.clone()));
+
+    let server = Server::new(path, conf, overrides)?;
+    let mdns_on_signal = mdns_service.clone();
+    let shutdown = async move {
+        shutdown_signal().await;
+        // unregister first so clients stop discovering a server that is going away
+        tokio::task::spawn_blocking(move || mdns_on_signal.lock().unwrap().shutdown())
+            .await
+            .ok();
+    };
+    let result = server.launch(shutdown).await;
+    mdns_service.lock().unwrap().shutdown();
+    result
+}
+
+/// Completes on ctrl-c, or on SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("SIGTERM handler could not be installed: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+async fn handle_config_show(path: &Path) -> anyhow::Result<()> {
+    let mut conf = Config::read_path(path).await?;
+    conf.apply(Overrides::from_env()?);
+    print!("{}", toml::to_string_pretty(&conf)?);
+    Ok(())
+}
+
+async fn handle_config_check(path: &Path) -> anyhow::Result<()> {
+    Config::load(path, Overrides::default()).await?;
+    println!("{}: configuration is valid", path.join(config::CONFIG_FNAME).display());
+    Ok(())
+}
+
+async fn handle_fsck(path: &Path, repair: Option<fsck::Repair>) -> anyhow::Result<()> {
+    let conf = Config::load(path, Overrides::default()).await?;
+    require_local_storage(&conf, "fsck")?;
+    let workdir = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let report = fsck::check(&workdir, &conf)?;
+        println!("{}", report);
+        match repair {
+            Some(action) => {
+                for line in fsck::repair(&workdir, &conf, report, action)? {
+                    println!("{}", line);
+                }
+            }
+            None if !report.is_clean() => {
+                anyhow::bail!("inconsistencies found, run again with --repair to fix them")
+            }
+            None => {}
+        }
+        Ok(())
+    })
+    .await?
+}
 
-    let server = Server::new(path, conf)?;
-    server.launch().await?;
-    mdns_service.shutdown();
+async fn handle_import(
+    path: &Path,
+    source: &Path,
+    author: &str,
+    mode: import::Mode,
+) -> anyhow::Result<()> {
+    let conf = Config::load(path, Overrides::default()).await?;
+    require_local_storage(&conf, "import")?;
+    let (workdir, source, author) = (path.to_path_buf(), source.to_path_buf(), author.to_string());
+    let summary = tokio::task::spawn_blocking(move || {
+        import::run(&workdir, &conf, &source, &author, mode)
+    })
+    .await??;
+    for line in &summary.files {
+        println!("{}", line);
+    }
+    println!("{}", summary);
+    Ok(())
+}
+
+async fn handle_export(path: &Path, archive: &Path, base: Option<&Path>) -> anyhow::Result<()> {
+    let conf = Config::load(path, Overrides::default()).await?;
+    require_local_storage(&conf, "export")?;
+    let (workdir, target, base) = (
+        path.to_path_buf(),
+        archive.to_path_buf(),
+        base.map(Path::to_path_buf),
+    );
+    let manifest = tokio::task::spawn_blocking(move || {
+        archive::export(&workdir, &conf, &target, base.as_deref())
+    })
+    .await??;
+    let included = manifest.blobs.iter().filter(|b| b.included).count();
+    println!(
+        "Exported {} file(s) to {}{}",
+        included,
+        archive.display(),
+        if manifest.is_incremental() {
+            format!(", {} unchanged since the base archive", manifest.blobs.len() - included)
+        } else {
+            String::new()
+        }
+    );
+    Ok(())
+}
+
+/// The maintenance commands work on the files in `uploads/`
+fn require_local_storage(conf: &Config, command: &str) -> anyhow::Result<()> {
+    if conf.storage.backend != config::StorageBackend::Local {
+        anyhow::bail!("'{}' only supports the local storage backend", command);
+    }
+    Ok(())
+}
+
+async fn handle_restore(archive: &Path, path: &Path) -> anyhow::Result<()> {
+    let (archive, workdir) = (archive.to_path_buf(), path.to_path_buf());
+    let manifest = tokio::task::spawn_blocking(move || archive::restore(&archive, &workdir)).await??;
+    println!(
+        "Restored {} file(s) to {} from an archive created {}",
+        manifest.blobs.len(),
+        path.display(),
+        manifest.created_at.format("%Y-%m-%d %H:%M:%S UTC")
+    );
     Ok(())
 }
 
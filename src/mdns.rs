@@ -1,9 +1,11 @@
 //! This module publishs mDNS service
 
+use std::collections::HashMap;
+
 use anyhow::Context;
-use mdns_sd::{DaemonStatus, ServiceDaemon, ServiceInfo};
+use mdns_sd::{DaemonEvent, DaemonStatus, IfKind, ServiceDaemon, ServiceInfo};
 
-use crate::{config::Config, utils};
+use crate::{config::{self, Config}, utils};
 
 pub struct MDnsService {
     daemon: ServiceDaemon,
@@ -34,22 +36,108 @@ impl MDnsService {
         }
     }
 }
+
+/// Returns the configured instance name, or `localshare-<hostname>` when none is set.
+pub fn instance_name(conf: &Config) -> String {
+    if let Some(name) = &conf.mdns.instance_name {
+        return name.clone();
+    }
+    match hostname::get() {
+        Ok(host) => format!("localshare-{}", host.to_string_lossy()),
+        Err(e) => {
+            log::warn!("Could not read hostname, falling back to plain instance name: {}", e);
+            "localshare".to_string()
+        }
+    }
+}
+
+/// Turns an instance name into a valid DNS label for the host record.
+fn host_label(instance_name: &str) -> String {
+    let label: String = instance_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "localshare".to_string()
+    } else {
+        label.chars().take(63).collect()
+    }
+}
+
+/// TXT records describing this server to clients that browse for it.
+fn txt_properties(conf: &Config) -> HashMap<String, String> {
+    let mut props = HashMap::new();
+    props.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    props.insert(
+        "auth".to_string(),
+        if conf.app.auth { "password" } else { "none" }.to_string(),
+    );
+    props.insert("tls".to_string(), "false".to_string());
+    props.insert("api".to_string(), config::API_V1_PATH.to_string());
+    props
+}
+
+/// Restricts the daemon to the interfaces selected in the network config.
+fn select_interfaces(mdns: &ServiceDaemon, conf: &Config) -> anyhow::Result<()> {
+    let net = &conf.network;
+    if !net.interfaces.is_empty() {
+        mdns.disable_interface(IfKind::All)?;
+        mdns.enable_interface(net.interfaces.iter().map(IfKind::from).collect::<Vec<_>>())?;
+    }
+    if !net.exclude_interfaces.is_empty() {
+        mdns.disable_interface(net.exclude_interfaces.iter().map(IfKind::from).collect::<Vec<_>>())?;
+    }
+    if !net.ipv6 {
+        mdns.disable_interface(IfKind::IPv6)?;
+    }
+    mdns.disable_interface(vec![IfKind::LoopbackV4, IfKind::LoopbackV6])?;
+    Ok(())
+}
+
 pub fn start_service(conf: &Config) -> anyhow::Result<MDnsService> {
     let mdns = ServiceDaemon::new()?;
-    let service_type = "_localshare._tcp.local.";
-    let instance_name = "localshare"; // This creates "localshare.local"
-    let host_name = format!("{}.local.", instance_name);
-    let service_info = ServiceInfo::new(
-        service_type,
-        instance_name,
+    select_interfaces(&mdns, conf).context("Could not select mdns interfaces")?;
+    let instance_name = instance_name(conf);
+    let host_name = format!("{}.local.", host_label(&instance_name));
+    let addrs = utils::advertised_ips(&conf.network);
+    if addrs.is_empty() {
+        log::warn!("mDNS: no usable network address found yet, waiting for one to appear");
+    }
+    let mut service_info = ServiceInfo::new(
+        config::MDNS_SERVICE_TYPE,
+        &instance_name,
         &host_name,
-        utils::get_local_ip(),
-        conf.app
-            .port
-            .parse()
-            .context("Could not parse port string to u16")?,
-        None,
+        &addrs[..],
+        conf.app.port,
+        txt_properties(conf),
     ).context("Could not initialize service info")?;
+    // Let the daemon follow address changes unless addresses are pinned in config
+    if conf.network.addresses.is_empty() {
+        service_info = service_info.enable_addr_auto();
+    }
+
+    // The daemon probes the names before announcing them and renames on conflict,
+    // so just report whatever name we end up with.
+    let monitor = mdns.monitor().context("Could not monitor mdns daemon")?;
+    std::thread::spawn(move || {
+        while let Ok(event) = monitor.recv() {
+            match event {
+                DaemonEvent::NameChange(change) => {
+                    log::warn!(
+                        "mDNS name conflict: '{}' is now announced as '{}'",
+                        change.original,
+                        change.new_name
+                    );
+                }
+                DaemonEvent::IpAdd(ip) => log::info!("mDNS: network address added: {}", ip),
+                DaemonEvent::IpDel(ip) => log::info!("mDNS: network address removed: {}", ip),
+                _ => {}
+            }
+        }
+    });
+
+    log::info!("mDNS: registering '{}' as {} on {:?}", instance_name, host_name, addrs);
     mdns.register(service_info).context("Could not register mdns service")?;
     Ok(MDnsService { daemon: mdns, shutdown: false })
 }
@@ -1,33 +1,130 @@
 //! This module handles QR Code generation for Host IP
 //!
 
-use std::path::PathBuf;
-
+use std::{io::Cursor, net::SocketAddr, path::{Path, PathBuf}, time::Duration};
 
+use anyhow::Context;
+use qrcode::{QrCode, render::{svg, unicode}};
 
 use crate::{config::{self, Config}, utils};
 
+/// Image formats a QR code can be rendered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+impl std::str::FromStr for QrFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "svg" => Ok(Self::Svg),
+            other => anyhow::bail!("unknown QR format '{}', expected png or svg", other),
+        }
+    }
+}
+
+/// Returns the URL clients should open to reach the server,
+/// or `None` when no usable local address exists.
+pub fn access_url(conf: &Config) -> Option<String> {
+    let ip = utils::get_local_ip(&conf.network)?;
+    // SocketAddr brackets IPv6 addresses
+    Some(format!("http://{}", SocketAddr::new(ip, conf.app.port)))
+}
+
+/// Renders `data` as a QR code image in the requested format
+pub fn render(data: &str, format: QrFormat) -> anyhow::Result<Vec<u8>> {
+    let code = QrCode::new(data.as_bytes()).context("QR encoding failed")?;
+    match format {
+        QrFormat::Png => {
+            let img = code.render::<image::Luma<u8>>().build();
+            let mut bytes = Cursor::new(Vec::new());
+            img.write_to(&mut bytes, image::ImageFormat::Png)
+                .context("PNG encoding failed")?;
+            Ok(bytes.into_inner())
+        }
+        QrFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build()
+            .into_bytes()),
+    }
+}
+
+/// Renders `data` as a QR code made of Unicode half blocks.
+/// Colors are inverted so the code scans on dark terminal backgrounds.
+pub fn render_terminal(data: &str) -> anyhow::Result<String> {
+    let code = QrCode::new(data.as_bytes()).context("QR encoding failed")?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// Prints the access QR code to the terminal.
+/// Returns true if it was printed.
+pub fn print_qr(conf: &Config) -> bool {
+    let Some(url) = access_url(conf) else {
+        log::error!("QR code printing failed: no usable local ip address");
+        return false;
+    };
+    match render_terminal(&url) {
+        Ok(code) => {
+            println!("{}\nScan to open {}\n", code, url);
+            true
+        }
+        Err(e) => {
+            log::error!("QR code printing failed: {}", e);
+            false
+        }
+    }
+}
+
 /// Tries to generate qr using configurated values
 /// Returns true if generation was successful
 /// Failure or success will be logged
-pub fn generate_qr(workdir : &PathBuf, conf : &Config) -> bool {
-    let ip = utils::get_local_ip();
-    // Format with port
-    let addr_string = format!("http://{}:{}", ip, conf.app.port);
-    let qr_path = workdir.join(&conf.path.r#static).join(config::QR_ACCESS_FNAME);
-
-    let code = match qrcode::QrCode::new(addr_string.as_bytes()) {
-        Ok(code) => code,
-        Err(e) => {
+pub fn generate_qr(workdir : &Path, conf : &Config) -> bool {
+    let Some(addr_string) = access_url(conf) else {
+        log::error!("QR code generation failed: no usable local ip address");
+        return false;
+    };
+    let static_dir = workdir.join(&conf.path.r#static);
+    let outputs = [
+        (QrFormat::Png, config::QR_ACCESS_FNAME),
+        (QrFormat::Svg, config::QR_ACCESS_SVG_FNAME),
+    ];
+    for (format, fname) in outputs {
+        let written = render(&addr_string, format)
+            .and_then(|bytes| Ok(std::fs::write(static_dir.join(fname), bytes)?));
+        if let Err(e) = written {
             log::error!("QR code generation failed: {}", e);
             return false;
         }
-    };
-
-    let img = code.render::<image::Luma<u8>>().build();
-    if let Err(e) = img.save(&qr_path) {
-        log::error!("QR code generation failed: {}", e);
-        return false;
     }
     true
 }
+
+/// How often the network is checked for a new preferred address
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Regenerates the QR code whenever the preferred local address changes,
+/// e.g. after switching from Ethernet to Wi-Fi. Runs until the task is dropped.
+pub async fn refresh_on_network_change(workdir: PathBuf, conf: Config) {
+    let mut current = utils::get_local_ip(&conf.network);
+    let mut interval = tokio::time::interval(NETWORK_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ip = utils::get_local_ip(&conf.network);
+        if ip != current {
+            log::info!("Preferred local address changed from {:?} to {:?}", current, ip);
+            if generate_qr(&workdir, &conf) {
+                print_qr(&conf);
+            }
+            current = ip;
+        }
+    }
+}
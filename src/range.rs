@@ -0,0 +1,186 @@
+//! This module handles `Range` requests for downloads (RFC 9110, section 14)
+//!
+//! A single byte range is served as `206 Partial Content`. Anything else the
+//! server does not understand, like several ranges, gets the whole file, which
+//! the RFC allows.
+
+use std::ops::Range;
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket_okapi::{
+    r#gen::OpenApiGenerator,
+    okapi::openapi3::{Parameter, ParameterValue},
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+
+/// The `Range` header of a request, resolved by [`RangeHeader::resolve`]
+#[derive(Debug, Default)]
+pub struct RangeHeader(Option<String>);
+
+/// What to send of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requested {
+    Full,
+    Partial(Range<u64>),
+    /// The range starts past the end of the file
+    Unsatisfiable,
+}
+
+impl RangeHeader {
+    /// The part of a file of `size` bytes the client asked for
+    pub fn resolve(&self, size: u64) -> Requested {
+        let Some(spec) = self.0.as_deref().and_then(|h| h.trim().strip_prefix("bytes=")) else {
+            return Requested::Full;
+        };
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Requested::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            // bytes=-500, the last 500 bytes
+            return match end.parse::<u64>() {
+                Ok(0) => Requested::Unsatisfiable,
+                Ok(_) if size == 0 => Requested::Unsatisfiable,
+                Ok(suffix) => Requested::Partial(size.saturating_sub(suffix)..size),
+                Err(_) => Requested::Full,
+            };
+        }
+        let Ok(start) = start.parse::<u64>() else {
+            return Requested::Full;
+        };
+        let end = match end {
+            "" => size,
+            // the last position is included
+            end => match end.parse::<u64>() {
+                Ok(last) if last >= start => last.saturating_add(1).min(size),
+                _ => return Requested::Full,
+            },
+        };
+        if start >= size {
+            return Requested::Unsatisfiable;
+        }
+        Requested::Partial(start..end)
+    }
+}
+
+/// `Content-Range` value of a partial response
+pub fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self(request.headers().get_one("Range").map(str::to_string)))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for RangeHeader {
+    fn from_request_input(
+        generator: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: "Range".to_string(),
+            location: "header".to_string(),
+            description: Some(
+                "A single byte range, `bytes=<first>-<last>`, `bytes=<first>-` or `bytes=-<length>`"
+                    .to_string(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: generator.json_schema::<String>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(header: &str, size: u64) -> Requested {
+        RangeHeader(Some(header.to_string())).resolve(size)
+    }
+
+    #[test]
+    fn no_header() {
+        assert_eq!(RangeHeader(None).resolve(100), Requested::Full);
+    }
+
+    #[test]
+    fn closed_range() {
+        assert_eq!(resolve("bytes=0-0", 100), Requested::Partial(0..1));
+        assert_eq!(resolve("bytes=10-19", 100), Requested::Partial(10..20));
+        assert_eq!(resolve(" bytes= 10 - 19 ", 100), Requested::Partial(10..20));
+        assert_eq!(resolve("bytes=0-99", 100), Requested::Partial(0..100));
+    }
+
+    #[test]
+    fn open_ended_range() {
+        assert_eq!(resolve("bytes=0-", 100), Requested::Partial(0..100));
+        assert_eq!(resolve("bytes=40-", 100), Requested::Partial(40..100));
+        assert_eq!(resolve("bytes=99-", 100), Requested::Partial(99..100));
+    }
+
+    #[test]
+    fn suffix_range() {
+        assert_eq!(resolve("bytes=-1", 100), Requested::Partial(99..100));
+        assert_eq!(resolve("bytes=-30", 100), Requested::Partial(70..100));
+        // longer than the file, the whole file
+        assert_eq!(resolve("bytes=-500", 100), Requested::Partial(0..100));
+        assert_eq!(resolve("bytes=-0", 100), Requested::Unsatisfiable);
+        assert_eq!(resolve("bytes=-10", 0), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn past_the_end() {
+        // the end is clamped to the file
+        assert_eq!(resolve("bytes=50-500", 100), Requested::Partial(50..100));
+        assert_eq!(resolve("bytes=0-18446744073709551615", 100), Requested::Partial(0..100));
+        // the start is not
+        assert_eq!(resolve("bytes=100-", 100), Requested::Unsatisfiable);
+        assert_eq!(resolve("bytes=100-200", 100), Requested::Unsatisfiable);
+        assert_eq!(resolve("bytes=0-", 0), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_ranges_get_the_whole_file() {
+        for header in [
+            "",
+            "bytes",
+            "bytes=",
+            "bytes=-",
+            "bytes=abc",
+            "bytes=a-10",
+            "bytes=10-b",
+            "bytes=-x",
+            "bytes=20-10",
+            "bytes=--5",
+            "bytes=1.5-2",
+            "bits=0-10",
+            "0-10",
+            // several ranges are not supported
+            "bytes=0-10, 20-30",
+        ] {
+            assert_eq!(resolve(header, 100), Requested::Full, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn content_range_header() {
+        assert_eq!(content_range(&(0..100), 100), "bytes 0-99/100");
+        assert_eq!(content_range(&(10..11), 100), "bytes 10-10/100");
+    }
+}
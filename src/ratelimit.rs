@@ -0,0 +1,455 @@
+//! This module limits how often a client may call the server, see `rate_limit`
+//!
+//! Requests are sorted into groups by their path, and every client IP has a
+//! token bucket per group holding `requests` tokens that refill over `period`
+//! seconds. A request finding no token is answered with `429 Too Many Requests`
+//! and `Retry-After`, denied clients get `403 Forbidden`.
+//!
+//! Fairings can not answer requests themselves, so refused requests are
+//! rerouted by [`refuse`] to a route that only sends the error.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    Data, Request,
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status, uri::Origin},
+    request::{FromRequest, Outcome},
+};
+
+use crate::{
+    client::client_ip,
+    config::{self, RateLimitConfig, RateRule},
+    error::ApiError,
+    server::SharedServer,
+};
+
+/// How often buckets that refilled completely are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests sharing a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Group {
+    Upload,
+    Download,
+    Auth,
+    Other,
+}
+
+impl Group {
+    fn of(path: &str) -> Self {
+        let endpoint = [config::API_V1_PATH, config::API_PATH]
+            .iter()
+            .find_map(|prefix| path.strip_prefix(prefix)?.strip_prefix('/'));
+        match endpoint.and_then(|e| e.split('/').next()) {
+            Some("upload") => Self::Upload,
+            Some("download") => Self::Download,
+            Some("auth" | "login") => Self::Auth,
+            _ => Self::Other,
+        }
+    }
+
+    /// Key of the group in `rate_limit`
+    fn name(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Download => "download",
+            Self::Auth => "auth",
+            Self::Other => "other",
+        }
+    }
+
+    fn rule(self, config: &RateLimitConfig) -> RateRule {
+        match self {
+            Self::Upload => config.upload,
+            Self::Download => config.download,
+            Self::Auth => config.auth,
+            Self::Other => config.other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tokens after refilling at `rule` until `now`
+    fn refilled(&self, rule: RateRule, now: Instant) -> f64 {
+        let per_second = rule.requests as f64 / rule.period as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_second).min(rule.requests as f64)
+    }
+
+    /// Takes a token, or returns the seconds until there is one
+    fn take(&mut self, rule: RateRule, now: Instant) -> Result<(), u64> {
+        self.tokens = self.refilled(rule, now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let per_second = rule.requests as f64 / rule.period as f64;
+        Err(((1.0 - self.tokens) / per_second).ceil() as u64)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<(IpAddr, Group), Bucket>,
+    pruned: Instant,
+}
+
+/// Rate limiting fairing, shared by every listening socket
+#[derive(Debug, Clone)]
+pub struct RateLimiter(Arc<Mutex<Buckets>>);
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Buckets {
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        })))
+    }
+
+    /// Whether `client` may request `path` at `now`, taking one of its tokens if so
+    fn check(
+        &self,
+        client: IpAddr,
+        path: &str,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        if config.deny.iter().any(|range| range.contains(client)) {
+            return Err(Refusal::Denied);
+        }
+        if config.allow.iter().any(|range| range.contains(client)) {
+            return Ok(());
+        }
+        self.take(client, Group::of(path), config, now)
+            .map_err(|retry_after| Refusal::RateLimited { retry_after })
+    }
+
+    /// Takes a token of `client` for `group`, or returns the seconds until there is one
+    fn take(
+        &self,
+        client: IpAddr,
+        group: Group,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> Result<(), u64> {
+        let mut state = self.0.lock().unwrap();
+        if now.duration_since(state.pruned) >= PRUNE_INTERVAL {
+            // a full bucket is the same as none
+            state.buckets.retain(|(_, group), bucket| {
+                let rule = group.rule(config);
+                bucket.refilled(rule, now) < rule.requests as f64
+            });
+            state.pruned = now;
+        }
+        let rule = group.rule(config);
+        state
+            .buckets
+            .entry((client, group))
+            .or_insert_with(|| Bucket {
+                tokens: rule.requests as f64,
+                updated: now,
+            })
+            .take(rule, now)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        // refused by an earlier fairing
+        if is_refused(request) {
+            return;
+        }
+        let Some(server) = request.rocket().state::<SharedServer>() else {
+            return;
+        };
+        let config = server.rate_limit();
+        if !config.enabled {
+            return;
+        }
+        let Some(client) = client_ip(request) else {
+            return;
+        };
+        let path = request.uri().path().to_string();
+        let Err(refusal) = self.check(client, &path, &config, Instant::now()) else {
+            return;
+        };
+        match refusal {
+            Refusal::Denied => {
+                log::info!("rate limit: refused {} {} from denied {}", request.method(), path, client);
+            }
+            Refusal::RateLimited { retry_after } => log::info!(
+                "rate limit: {} exceeded rate_limit.{} with {} {}, retry in {}s",
+                client,
+                Group::of(&path).name(),
+                request.method(),
+                path,
+                retry_after
+            ),
+        }
+        refuse(request, refusal);
+    }
+}
+
+/// Why a request is not served
+#[derive(Debug, Clone, Copy)]
+pub enum Refusal {
+    /// The client may not use the server
+    Denied,
+    RateLimited { retry_after: u64 },
+}
+
+impl From<Refusal> for ApiError {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Denied => Self::AccessDenied,
+            Refusal::RateLimited { retry_after } => Self::RateLimited { retry_after },
+        }
+    }
+}
+
+/// Refusal of the current request, kept in its local cache
+struct Refused(OnceLock<Refusal>);
+
+fn refusal_of(request: &Request<'_>) -> Option<Refusal> {
+    request.local_cache(|| Refused(OnceLock::new())).0.get().copied()
+}
+
+pub fn is_refused(request: &Request<'_>) -> bool {
+    refusal_of(request).is_some()
+}
+
+/// Answers `request` with `refusal` instead of running its route.
+/// The first refusal of a request is the one sent.
+pub fn refuse(request: &mut Request<'_>, refusal: Refusal) {
+    let _ = request.local_cache(|| Refused(OnceLock::new())).0.set(refusal);
+    request.set_method(Method::Get);
+    // the route of `refused`
+    request.set_uri(Origin::parse_owned(format!("{}/refused", config::API_PATH)).unwrap());
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Refusal {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match refusal_of(request) {
+            Some(refusal) => Outcome::Success(refusal),
+            // requested directly
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+#[rocket::get("/refused")]
+fn refused(refusal: Refusal) -> ApiError {
+    refusal.into()
+}
+
+/// The route refused requests are sent to, mounted at [`config::API_PATH`]
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![refused]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use rocket::{fairing::AdHoc, http::Status, local::blocking::Client};
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 11));
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            allow: Vec::new(),
+            upload: RateRule::new(2, 60),
+            auth: RateRule::new(1, 10),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn groups() {
+        for (path, group) in [
+            ("/api/v1/upload", Group::Upload),
+            ("/api/upload", Group::Upload),
+            ("/api/v1/download/8f1c0a5e-8a7c-4c8e-9a43-2f1d9c3b7e10", Group::Download),
+            ("/api/download/8f1c0a5e-8a7c-4c8e-9a43-2f1d9c3b7e10", Group::Download),
+            ("/api/v1/auth", Group::Auth),
+            ("/api/v1/login", Group::Auth),
+            ("/api/v1/files", Group::Other),
+            ("/api/v1/uploads", Group::Other),
+            ("/api/v1", Group::Other),
+            // pages
+            ("/upload", Group::Other),
+            ("/login", Group::Other),
+            ("/", Group::Other),
+            ("/apix/upload", Group::Other),
+        ] {
+            assert_eq!(Group::of(path), group, "{}", path);
+        }
+    }
+
+    #[test]
+    fn bucket_holds_a_period_of_requests() {
+        let rule = RateRule::new(3, 60);
+        let now = Instant::now();
+        let mut bucket = Bucket { tokens: 3.0, updated: now };
+        for _ in 0..3 {
+            assert_eq!(bucket.take(rule, now), Ok(()));
+        }
+        // one token every 20 seconds
+        assert_eq!(bucket.take(rule, now), Err(20));
+        assert_eq!(bucket.take(rule, now + Duration::from_secs(5)), Err(15));
+        assert_eq!(bucket.take(rule, now + Duration::from_secs(20)), Ok(()));
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let rule = RateRule::new(3, 10);
+        let now = Instant::now();
+        let mut bucket = Bucket { tokens: 0.0, updated: now };
+        assert_eq!(bucket.take(rule, now), Err(4));
+    }
+
+    #[test]
+    fn idle_buckets_refill_up_to_the_limit() {
+        let rule = RateRule::new(3, 60);
+        let now = Instant::now();
+        let bucket = Bucket { tokens: 0.0, updated: now };
+        assert_eq!(bucket.refilled(rule, now + Duration::from_secs(30)), 1.5);
+        assert_eq!(bucket.refilled(rule, now + Duration::from_secs(3600)), 3.0);
+    }
+
+    #[test]
+    fn clients_and_groups_have_their_own_buckets() {
+        let limiter = RateLimiter::new();
+        let config = config();
+        let now = Instant::now();
+        let upload = "/api/v1/upload";
+        assert!(limiter.check(CLIENT, upload, &config, now).is_ok());
+        assert!(limiter.check(CLIENT, upload, &config, now).is_ok());
+        assert!(matches!(
+            limiter.check(CLIENT, upload, &config, now),
+            Err(Refusal::RateLimited { retry_after: 30 })
+        ));
+        assert!(limiter.check(OTHER, upload, &config, now).is_ok());
+        assert!(limiter.check(CLIENT, "/api/v1/auth", &config, now).is_ok());
+        assert!(limiter.check(CLIENT, "/api/v1/auth", &config, now).is_err());
+        assert!(limiter.check(CLIENT, "/api/v1/files", &config, now).is_ok());
+        // refilled after idle time
+        let later = now + Duration::from_secs(30);
+        assert!(limiter.check(CLIENT, upload, &config, later).is_ok());
+        assert!(limiter.check(CLIENT, upload, &config, later).is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let limiter = RateLimiter::new();
+        let config = RateLimitConfig {
+            allow: vec!["192.168.1.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.10".parse().unwrap()],
+            ..config()
+        };
+        let now = Instant::now();
+        assert!(matches!(
+            limiter.check(CLIENT, "/", &config, now),
+            Err(Refusal::Denied)
+        ));
+        // allowed clients are never limited
+        for _ in 0..10 {
+            assert!(limiter.check(OTHER, "/api/v1/auth", &config, now).is_ok());
+        }
+        assert!(limiter.0.lock().unwrap().buckets.is_empty());
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limiter = RateLimiter::new();
+        let config = config();
+        let now = Instant::now();
+        limiter.check(CLIENT, "/api/v1/upload", &config, now).unwrap();
+        limiter.check(OTHER, "/api/v1/files", &config, now).unwrap();
+        assert_eq!(limiter.0.lock().unwrap().buckets.len(), 2);
+        // the upload bucket refills within a minute, the 600 requests of `other` take longer
+        let later = now + PRUNE_INTERVAL + Duration::from_secs(1);
+        limiter.check(OTHER, "/api/v1/files", &config, later).unwrap();
+        let buckets = &limiter.0.lock().unwrap().buckets;
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key(&(OTHER, Group::Other)));
+    }
+
+    #[rocket::get("/target")]
+    fn target() -> &'static str {
+        "served"
+    }
+
+    fn client(refusals: Vec<Refusal>) -> Client {
+        let mut rocket = rocket::build()
+            .mount("/", rocket::routes![target])
+            .mount(config::API_PATH, routes());
+        for refusal in refusals {
+            rocket = rocket.attach(AdHoc::on_request("Refuse", move |request, _| {
+                Box::pin(async move { refuse(request, refusal) })
+            }));
+        }
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn refused_requests_get_the_error_instead_of_their_route() {
+        let client = client(vec![Refusal::RateLimited { retry_after: 7 }]);
+        let response = client.post("/target").dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("7"));
+        assert!(response.into_string().unwrap().contains("rate_limited"));
+    }
+
+    #[test]
+    fn the_first_refusal_is_sent() {
+        let client = client(vec![
+            Refusal::Denied,
+            Refusal::RateLimited { retry_after: 7 },
+        ]);
+        let response = client.get("/target").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(response.headers().get_one("Retry-After").is_none());
+    }
+
+    #[test]
+    fn requests_that_are_not_refused_are_served() {
+        let client = client(Vec::new());
+        assert_eq!(client.get("/target").dispatch().into_string().unwrap(), "served");
+        // the route only answers refused requests
+        assert_eq!(client.get("/api/refused").dispatch().status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,117 @@
+//! This module applies changes of LocalShare.toml while the server runs
+//!
+//! The file is checked for modifications periodically, and SIGHUP forces a reload.
+//! Only settings that are safe to swap under running transfers are applied,
+//! see [`crate::server::Server::reload_config`].
+
+use std::{path::Path, time::{Duration, SystemTime}};
+
+use crate::{
+    config::{CONFIG_FNAME, Config},
+    server::SharedServer,
+    session::SharedSessionStorage,
+};
+
+/// How often LocalShare.toml is checked for modifications
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified_at(workdir: &Path) -> Option<SystemTime> {
+    std::fs::metadata(workdir.join(CONFIG_FNAME))
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reloads the config whenever it changes on disk or SIGHUP arrives.
+/// Runs until the task is aborted.
+pub async fn watch(server: SharedServer, sessions: SharedSessionStorage) {
+    let workdir = server.workdir().to_path_buf();
+    let mut last_modified = modified_at(&workdir);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    let mut hangup = hangup_signal();
+    loop {
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = hangup.recv() => true,
+        };
+        let modified = modified_at(&workdir);
+        if !forced && modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        if forced {
+            log::info!("reload: SIGHUP received, reloading {}", CONFIG_FNAME);
+        } else {
+            log::info!("reload: {} changed on disk, reloading", CONFIG_FNAME);
+        }
+        if let Err(e) = reload(&server, &sessions).await {
+            log::error!("reload: keeping the current configuration: {:#}", e);
+        }
+    }
+}
+
+/// Reads the config again and applies it to the running server.
+/// An invalid file leaves the running configuration untouched.
+pub async fn reload(server: &SharedServer, sessions: &SharedSessionStorage) -> anyhow::Result<()> {
+    let new = Config::load(server.workdir(), server.overrides().clone()).await?;
+    let changes = server.reload_config(new);
+    if changes.auth_enabled {
+        // sessions handed out while auth was off were never authenticated
+        sessions.lock().await.clear();
+    }
+    if changes.applied.is_empty() && changes.needs_restart.is_empty() {
+        log::info!("reload: no changes");
+    }
+    if !changes.applied.is_empty() {
+        log::info!("reload: applied {}", changes.applied.join(", "));
+    }
+    if !changes.needs_restart.is_empty() {
+        log::warn!(
+            "reload: {} changed, restart the server to apply",
+            changes.needs_restart.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    async fn recv(&mut self) {
+        if let Some(signal) = &mut self.0
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending::<()>().await
+
+    }
+}
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{SignalKind, signal};
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => Hangup(Some(signal)),
+        Err(e) => {
+            log::warn!("reload: SIGHUP handler could not be installed: {}", e);
+            Hangup(None)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    async fn recv(&mut self) {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {
+    Hangup
+}
@@ -1,315 +1,1061 @@
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 use chrono::Utc;
 use rocket::{FromForm};
 use rocket::form::Form;
 use rocket::http::{Cookie, CookieJar};
-use rocket::http::{ContentType, Status, hyper::header};
+use rocket::http::{ContentType, Header, Status, hyper::header};
 use rocket::response::Redirect;
 use rocket::{
-    Data, Response, Rocket, State,
+    Data, Response, Rocket, State, delete,
     data::ToByteUnit,
+    fairing::AdHoc,
     fs::NamedFile,
+    get, post, put,
     response::{
         Responder,
-        status::{self, Custom},
+        status::NoContent,
         stream::{One, ReaderStream},
     },
     routes,
     serde::json::Json,
 };
+use rocket_okapi::{
+    JsonSchema,
+    r#gen::OpenApiGenerator,
+    handlers::OpenApiHandler,
+    okapi::{
+        openapi3::{OpenApi, Responses},
+        schemars::schema::{InstanceType, SchemaObject},
+    },
+    openapi, openapi_get_routes_spec,
+    response::OpenApiResponderInner,
+    swagger_ui::{SwaggerUIConfig, make_swagger_ui},
+    util::add_schema_response,
+};
 use serde::{Deserialize, Serialize};
-use tokio::{fs::File, io, sync::Mutex};
+use tokio::{fs::File, io::{self, AsyncWriteExt}, sync::{Mutex, oneshot}, task::JoinSet};
 use uuid::Uuid;
 
-use crate::session::{SessionId, SessionStorage};
+use crate::session::{SessionId, SessionStorage, SharedSessionStorage};
 use crate::{
     assets::StaticFile,
-    config::{self, Config},
-    fm::{FileManager, record::Record},
+    compression::{self, AcceptEncoding},
+    crypto::{self, MasterKey},
+    client::ClientIp,
+    access::AccessControl,
+    config::{self, AccessConfig, Config, LimitsConfig, NetworkConfig, Overrides, RateLimitConfig},
+    error::{self, ApiError},
+    digest::{DigestHeaders, HashingWriter, Sha256Digest},
+    fm::{AsyncFileManager, FileManager, record::Record},
+    qr::QrFormat,
+    range::{RangeHeader, Requested},
+    ratelimit::{self, RateLimiter},
+    storage::{BlobReader, LocalStorage, Storage},
+    transfer::{Tracked, TransferTracker},
 };
 
-#[allow(dead_code)]
+/// Server state shared by every listening socket.
+/// Immutable apart from the settings behind [`Server::runtime`], so requests never wait for each other.
+pub type SharedServer = Arc<Server>;
+
+/// Seconds connections get to close after the grace period ran out
+const SHUTDOWN_MERCY: u32 = 5;
+
 pub struct Server {
     wd: PathBuf,
+    // as loaded on startup, reloaded values are in `runtime`
     config: Config,
-    fm: FileManager,
+    fm: AsyncFileManager,
+    // content of the files, the shared directory while one is shared
+    storage: Arc<dyn Storage>,
+    // opens encrypted files, and encrypts uploads when `encryption.enabled`
+    key: Option<Arc<MasterKey>>,
+    runtime: RwLock<Runtime>,
+    // uploads and downloads in flight, paced to `runtime.limits`
+    transfers: TransferTracker,
+    // re-applied when the config file is reloaded
+    overrides: Overrides,
+}
+
+/// Settings that change when the config file is reloaded
+#[derive(Debug, Clone)]
+struct Runtime {
+    limits: LimitsConfig,
+    rate_limit: RateLimitConfig,
+    access: AccessConfig,
     admin_password: Option<String>,
 }
 
+/// Outcome of applying a reloaded config to a running server
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    /// Keys whose new values are in effect
+    pub applied: Vec<&'static str>,
+    /// Keys that changed but keep their old values until a restart
+    pub needs_restart: Vec<&'static str>,
+    /// Auth was switched on, sessions issued without a password must go
+    pub auth_enabled: bool,
+}
+
+fn read_admin_password() -> anyhow::Result<String> {
+    let password = std::env::var("LOCALSHARE_PASSWORD").map_err(|_| {
+        anyhow::anyhow!(
+            "LOCALSHARE_PASSWORD environment variable is not set. \
+             Set it before starting the server in auth mode."
+        )
+    })?;
+    if password.is_empty() {
+        anyhow::bail!(
+            "LOCALSHARE_PASSWORD environment variable is set but empty. \
+             Provide a non-empty password."
+        );
+    }
+    Ok(password)
+}
+
+/// Removes uploads that were cut off by a crash or kill
+fn clean_staging(staging_dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(staging_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => log::info!("removed unfinished upload {}", entry.path().display()),
+            Err(e) => log::warn!(
+                "could not remove unfinished upload {}: {}",
+                entry.path().display(),
+                e
+            ),
+        }
+    }
+}
+
 impl Server {
-    pub fn new(workdir: &Path, config: Config) -> anyhow::Result<Self> {
-        let fm = FileManager::new(workdir, config.clone())?;
-        let admin_password = if config.app.auth {
-            let password = std::env::var("LOCALSHARE_PASSWORD").map_err(|_| {
-                anyhow::anyhow!(
-                    "LOCALSHARE_PASSWORD environment variable is not set. \
-                     Set it before starting the server in auth mode."
-                )
-            })?;
-            if password.is_empty() {
-                anyhow::bail!(
-                    "LOCALSHARE_PASSWORD environment variable is set but empty. \
-                     Provide a non-empty password."
-                );
+    pub fn new(workdir: &Path, config: Config, overrides: Overrides) -> anyhow::Result<Self> {
+        let mut fm = FileManager::new(workdir, config.clone())?;
+        clean_staging(&workdir.join(&config.path.uploads).join(config::STAGING_DIR));
+        let storage: Arc<dyn Storage> = match &config.share.directory {
+            Some(directory) => {
+                fm.share(workdir.join(directory))?;
+                Arc::new(LocalStorage::new(workdir.join(directory)))
             }
-            Some(password)
+            None => crate::storage::from_config(workdir, &config)?,
+        };
+        let key = crypto::load_key(workdir, &config.encryption, &fm)?.map(Arc::new);
+        let admin_password = if config.app.auth {
+            Some(read_admin_password()?)
         } else {
             None
         };
         Ok(Self {
             wd: workdir.to_path_buf(),
-            config: config,
-            fm,
-            admin_password,
+            transfers: TransferTracker::new(config.limits.clone()),
+            runtime: RwLock::new(Runtime {
+                limits: config.limits.clone(),
+                rate_limit: config.rate_limit.clone(),
+                access: config.access.clone(),
+                admin_password,
+            }),
+            config,
+            fm: AsyncFileManager::new(fm),
+            storage,
+            key,
+            overrides,
         })
     }
 
-    pub async fn launch(self) -> anyhow::Result<()> {
+    pub fn workdir(&self) -> &Path {
+        &self.wd
+    }
+
+    pub fn overrides(&self) -> &Overrides {
+        &self.overrides
+    }
+
+    pub fn shared_dir(&self) -> Option<PathBuf> {
+        self.fm.shared_dir().map(Path::to_path_buf)
+    }
+
+    pub fn rescan_interval(&self) -> u32 {
+        self.config.share.rescan_interval
+    }
+
+    /// See [`FileManager::replace_shared_index`]
+    pub fn replace_shared_index(&self, index: crate::share::SharedIndex) -> (usize, usize) {
+        self.fm.replace_shared_index(index)
+    }
+
+    fn runtime(&self) -> Runtime {
+        self.runtime.read().unwrap().clone()
+    }
+
+    /// Rate limits in effect, see [`crate::ratelimit`]
+    pub fn rate_limit(&self) -> RateLimitConfig {
+        self.runtime.read().unwrap().rate_limit.clone()
+    }
+
+    /// Networks clients may connect from, see [`crate::access`]
+    pub fn access(&self) -> AccessConfig {
+        self.runtime.read().unwrap().access.clone()
+    }
+
+    pub fn network(&self) -> &NetworkConfig {
+        &self.config.network
+    }
+
+    /// Replaces the limits, running transfers included
+    fn set_limits(&self, limits: LimitsConfig) {
+        self.transfers.set_limits(limits.clone());
+        self.runtime.write().unwrap().limits = limits;
+    }
+
+    fn static_dir(&self) -> PathBuf {
+        self.wd.join(&self.config.path.r#static)
+    }
+
+    /// Where uploads are received before they are handed to the storage
+    fn staging_dir(&self) -> PathBuf {
+        self.wd.join(&self.config.path.uploads).join(config::STAGING_DIR)
+    }
+
+    /// Applies the settings of a reloaded config that can change at runtime.
+    /// Everything else keeps its current value and is reported as needing a restart.
+    pub fn reload_config(&self, new: Config) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        let mut runtime = self.runtime.write().unwrap();
+        if new.limits != runtime.limits {
+            self.transfers.set_limits(new.limits.clone());
+            runtime.limits = new.limits;
+            changes.applied.push("limits");
+        }
+        if new.rate_limit != runtime.rate_limit {
+            runtime.rate_limit = new.rate_limit;
+            changes.applied.push("rate_limit");
+        }
+        if new.access != runtime.access {
+            runtime.access = new.access;
+            changes.applied.push("access");
+        }
+        if new.app.auth != runtime.admin_password.is_some() {
+            if new.app.auth {
+                match read_admin_password() {
+                    Ok(password) => {
+                        runtime.admin_password = Some(password);
+                        changes.auth_enabled = true;
+                        changes.applied.push("app.auth");
+                    }
+                    Err(e) => log::error!("reload: auth stays disabled: {}", e),
+                }
+            } else {
+                runtime.admin_password = None;
+                changes.applied.push("app.auth");
+            }
+        }
+        if new.app.port != self.config.app.port {
+            changes.needs_restart.push("app.port");
+        }
+        if new.app.debug != self.config.app.debug {
+            changes.needs_restart.push("app.debug");
+        }
+        if new.path != self.config.path {
+            changes.needs_restart.push("path");
+        }
+        if new.mdns != self.config.mdns {
+            changes.needs_restart.push("mdns");
+        }
+        if new.network != self.config.network {
+            changes.needs_restart.push("network");
+        }
+        if new.shutdown != self.config.shutdown {
+            changes.needs_restart.push("shutdown");
+        }
+        if new.share != self.config.share {
+            changes.needs_restart.push("share");
+        }
+        if new.storage != self.config.storage {
+            changes.needs_restart.push("storage");
+        }
+        if new.encryption != self.config.encryption {
+            changes.needs_restart.push("encryption");
+        }
+        changes
+    }
+
+    /// Removes uploads that were recorded but not stored when the server stopped
+    async fn clean_pending(&self) {
+        if self.fm.is_read_only() {
+            return;
+        }
+        let pending = match self.fm.pending_records().await {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::warn!("could not look up unfinished uploads: {}", e);
+                return;
+            }
+        };
+        for uuid in pending {
+            let key = uuid.to_string();
+            match self.storage.delete(&key).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    log::warn!("could not remove unfinished upload {}: {}", key, e);
+                    continue;
+                }
+                _ => {}
+            }
+            match self.fm.delete_record(uuid).await {
+                Ok(_) => log::info!("removed unfinished upload {}", key),
+                Err(e) => log::warn!(
+                    "could not remove the record of unfinished upload {}: {}",
+                    key,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Serves until `shutdown` completes or a listener fails.
+    /// On shutdown, listeners stop accepting connections and in-flight
+    /// transfers get `shutdown.grace_period` seconds to finish.
+    pub async fn launch(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
         let default_config = if self.config.app.debug {
             rocket::Config::debug_default()
         } else {
             rocket::Config::release_default()
         };
-        let config = rocket::Config {
-            port: self.config.app.port.parse()?,
-            address: "0.0.0.0".parse().unwrap(),
+        let base_config = rocket::Config {
+            port: self.config.app.port,
             log_level: rocket::config::LogLevel::Normal,
+            // every listener must accept the cookies issued by the others
+            secret_key: rocket::config::SecretKey::generate()
+                .ok_or_else(|| anyhow::anyhow!("Could not generate secret key"))?,
+            // signals are handled by the caller through `shutdown`
+            shutdown: rocket::config::Shutdown {
+                ctrlc: false,
+                signals: Default::default(),
+                grace: self.config.shutdown.grace_period,
+                mercy: SHUTDOWN_MERCY,
+                ..Default::default()
+            },
             ..default_config
         };
-        let _ = Rocket::custom(config)
-            .manage(Mutex::new(self))
-            .manage(Mutex::new(SessionStorage::new()))
-            .mount(
-                "/",
-                routes![
-                    index,
-                    upload,
-                    login_page,
-                    qr,
-                    route_api_list,
-                    route_api_upload,
-                    route_api_download,
-                    route_api_delete,
-                    route_api_login,
-                    route_api_session,
-                    route_api_auth,
-                    route_api_logout
-                ],
-            )
-            .launch()
-            .await?;
-        Ok(())
+        self.clean_pending().await;
+        let mut addrs = self.config.network.bind_addrs(base_config.port);
+        let unix_socket = self.config.network.unix_socket.as_ref().map(|p| self.wd.join(p));
+
+        // Requests arriving on the unix socket are forwarded to a loopback-only listener.
+        // It binds an ephemeral port and reports the port once bound, so no other
+        // process can take the port in between.
+        let mut forwarder = None;
+        let mut internal = None;
+        if let Some(socket_path) = unix_socket {
+            let (bound, target) = oneshot::channel();
+            addrs.push(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+            internal = Some(bound);
+            let task = tokio::spawn(crate::listener::forward_unix_socket(
+                socket_path.clone(),
+                target,
+            ));
+            forwarder = Some((task, socket_path));
+        }
+        if addrs.is_empty() {
+            anyhow::bail!("No bind address or unix socket configured");
+        }
+
+        let transfers = self.transfers.clone();
+        let server: SharedServer = Arc::new(self);
+        let sessions: SharedSessionStorage = Arc::new(Mutex::new(SessionStorage::new()));
+        let access = AccessControl::new();
+        let limiter = RateLimiter::new();
+        let reloader = tokio::spawn(crate::reload::watch(server.clone(), sessions.clone()));
+        let rescanner = tokio::spawn(crate::share::watch(server.clone()));
+        let mut shutdown_handles = Vec::new();
+        let mut listeners = JoinSet::new();
+        let internal_index = addrs.len() - 1;
+        for (i, addr) in addrs.into_iter().enumerate() {
+            let config = rocket::Config {
+                address: addr.ip(),
+                port: addr.port(),
+                ..base_config.clone()
+            };
+            let mut rocket = build_rocket(
+                config,
+                server.clone(),
+                sessions.clone(),
+                transfers.clone(),
+                access.clone(),
+                limiter.clone(),
+            );
+            if i == internal_index
+                && let Some(bound) = internal.take()
+            {
+                // Rocket updates its config with the port it actually bound
+                rocket = rocket.attach(AdHoc::on_liftoff("Unix socket bridge", |rocket| {
+                    Box::pin(async move {
+                        let config = rocket.config();
+                        let _ = bound.send(SocketAddr::new(config.address, config.port));
+                    })
+                }));
+            }
+            let rocket = rocket.ignite().await?;
+            shutdown_handles.push(rocket.shutdown());
+            listeners.spawn(rocket.launch());
+        }
+
+        let handles = shutdown_handles.clone();
+        let grace_period = base_config.shutdown.grace;
+        let tracker = transfers.clone();
+        let shutdown_task = tokio::spawn(async move {
+            shutdown.await;
+            log::info!(
+                "Shutting down: no new requests, waiting up to {}s for {} active transfer(s)",
+                grace_period,
+                tracker.active()
+            );
+            for handle in handles {
+                handle.notify();
+            }
+        });
+
+        // Once one listener stops, whether on shutdown or on error, stop them all
+        let mut result = Ok(());
+        while let Some(joined) = listeners.join_next().await {
+            if let Err(e) = joined?
+                && result.is_ok()
+            {
+                result = Err(anyhow::Error::from(e));
+            }
+            for handle in &shutdown_handles {
+                handle.clone().notify();
+            }
+        }
+        reloader.abort();
+        rescanner.abort();
+        shutdown_task.abort();
+        if transfers.active() > 0 {
+            log::warn!("{} transfer(s) were cut off by shutdown", transfers.active());
+        }
+        if let Some((task, socket_path)) = forwarder {
+            task.abort();
+            let _ = std::fs::remove_file(socket_path);
+        }
+        result
     }
 }
 
+fn build_rocket(
+    config: rocket::Config,
+    server: SharedServer,
+    sessions: SharedSessionStorage,
+    transfers: TransferTracker,
+    access: AccessControl,
+    limiter: RateLimiter,
+) -> Rocket<rocket::Build> {
+    let (api_routes, spec) = api();
+    let docs_path = format!("{}/docs", config::API_PATH);
+    Rocket::custom(config)
+        .manage(server)
+        .manage(sessions)
+        .manage(transfers)
+        .attach(error::RequestIdFairing)
+        // refused clients do not count against the rate limits
+        .attach(access)
+        .attach(limiter)
+        .register(config::API_PATH, error::catchers())
+        .mount("/", routes![index, upload, login_page, share_page, e2e_script, qr, qr_file])
+        .mount(config::API_V1_PATH, api_routes.clone())
+        // unversioned aliases, kept for clients written against older releases
+        .mount(config::API_PATH, api_routes)
+        .mount(config::API_PATH, ratelimit::routes())
+        .mount(
+            config::API_PATH,
+            vec![OpenApiHandler::new(spec).into_route("/openapi.json")],
+        )
+        .mount(
+            docs_path,
+            make_swagger_ui(&SwaggerUIConfig {
+                url: format!("{}/openapi.json", config::API_PATH),
+                ..Default::default()
+            }),
+        )
+}
+
+/// Routes of the JSON API and the OpenAPI document describing them
+fn api() -> (Vec<rocket::Route>, OpenApi) {
+    let (routes, mut spec) = openapi_get_routes_spec![
+        route_api_info,
+        route_api_list,
+        route_api_upload,
+        route_api_download,
+        route_api_delete,
+        route_api_get_limits,
+        route_api_set_limits,
+        route_api_login,
+        route_api_session,
+        route_api_auth,
+        route_api_logout
+    ];
+    spec.info.title = "LocalShare".to_string();
+    spec.info.description = Some(env!("CARGO_PKG_DESCRIPTION").to_string());
+    spec.servers = vec![rocket_okapi::okapi::openapi3::Server {
+        url: config::API_V1_PATH.to_string(),
+        ..Default::default()
+    }];
+    (routes, spec)
+}
+
 #[rocket::get("/")]
-async fn index(server: &State<Mutex<Server>>) -> io::Result<NamedFile> {
-    let static_dir = {
-        let server = server.lock().await;
-        server.wd.join(server.config.path.r#static.clone())
-    };
-    rocket::fs::NamedFile::open(static_dir.join(PathBuf::from(StaticFile::Index))).await
+async fn index(server: &State<SharedServer>) -> io::Result<NamedFile> {
+    rocket::fs::NamedFile::open(server.static_dir().join(PathBuf::from(StaticFile::Index))).await
 }
 #[rocket::get("/upload")]
-async fn upload(server: &State<Mutex<Server>>) -> io::Result<NamedFile> {
-    let static_dir = {
-        let server = server.lock().await;
-        server.wd.join(server.config.path.r#static.clone())
-    };
-    rocket::fs::NamedFile::open(static_dir.join(PathBuf::from(StaticFile::Upload))).await
+async fn upload(server: &State<SharedServer>) -> io::Result<NamedFile> {
+    rocket::fs::NamedFile::open(server.static_dir().join(PathBuf::from(StaticFile::Upload))).await
+}
+
+/// Page decrypting an end-to-end encrypted file with the key in the link
+#[rocket::get("/share/<_file_uuid>")]
+async fn share_page(server: &State<SharedServer>, _file_uuid: Uuid) -> io::Result<NamedFile> {
+    rocket::fs::NamedFile::open(server.static_dir().join(PathBuf::from(StaticFile::Share))).await
+}
+
+/// Encryption shared by the upload and share pages
+#[rocket::get("/e2e.js")]
+async fn e2e_script(server: &State<SharedServer>) -> io::Result<NamedFile> {
+    rocket::fs::NamedFile::open(server.static_dir().join(PathBuf::from(StaticFile::E2e))).await
 }
 
 #[rocket::get("/login")]
-async fn login_page(server: &State<Mutex<Server>>) -> io::Result<NamedFile> {
-    let static_dir = {
-        let server = server.lock().await;
-        server.wd.join(server.config.path.r#static.clone())
-    };
-    rocket::fs::NamedFile::open(static_dir.join(PathBuf::from(StaticFile::Login))).await
+async fn login_page(server: &State<SharedServer>) -> io::Result<NamedFile> {
+    rocket::fs::NamedFile::open(server.static_dir().join(PathBuf::from(StaticFile::Login))).await
 }
 
 
-#[rocket::get("/qr")]
-async fn qr(server: &State<Mutex<Server>>) -> Result<NamedFile, Status> {
-    let path_to_qr = {
-        let server = server.lock().await;
-        // $WORK_DIR/$static/$qr_filename
-        server
-            .wd
-            .join(server.config.path.r#static.clone())
-            .join(config::QR_ACCESS_FNAME)
+fn parse_qr_format(format: Option<&str>) -> Result<QrFormat, ApiError> {
+    format
+        .map(str::parse)
+        .transpose()
+        .map(Option::unwrap_or_default)
+        .map_err(|_| ApiError::BadRequest("unknown qr format, expected png or svg".into()))
+}
+
+fn qr_content_type(format: QrFormat) -> ContentType {
+    match format {
+        QrFormat::Png => ContentType::PNG,
+        QrFormat::Svg => ContentType::SVG,
+    }
+}
+
+#[rocket::get("/qr?<format>")]
+async fn qr(
+    server: &State<SharedServer>,
+    format: Option<&str>,
+) -> Result<NamedFile, ApiError> {
+    let fname = match parse_qr_format(format)? {
+        QrFormat::Png => config::QR_ACCESS_FNAME,
+        QrFormat::Svg => config::QR_ACCESS_SVG_FNAME,
     };
+    // $WORK_DIR/$static/$qr_filename
+    let path_to_qr = server.static_dir().join(fname);
     NamedFile::open(&path_to_qr)
         .await
-        .map_err(|_| Status::NotFound)
-}
-#[rocket::get("/api/list")]
-async fn route_api_list(
-    server: &State<Mutex<Server>>,
-) -> std::result::Result<Json<Vec<Record>>, Status> {
-    let list = {
-        let mut server = server.lock().await;
-        server.fm.get_all_records()
-    };
-    match list {
-        Ok(list) => Ok(Json(list)),
-        Err(e) => {
-            log::error!("/api/list: {}", e);
-            Err(Status::InternalServerError)
-        }
-    }
+        .map_err(|_| ApiError::NotFound("qr code"))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// QR code pointing at the download URL of a single file.
+/// The URL uses the same address as the access QR code, never the `Host` a client sent,
+/// so nobody can have the server hand out a code pointing elsewhere.
+#[rocket::get("/qr/<file_uuid>?<format>")]
+async fn qr_file(
+    server: &State<SharedServer>,
+    file_uuid: Uuid,
+    format: Option<&str>,
+) -> Result<(ContentType, Vec<u8>), ApiError> {
+    let format = parse_qr_format(format)?;
+    let record = server
+        .fm
+        .get_record_by_uuid(file_uuid)
+        .await?
+        .ok_or(ApiError::NotFound("file record"))?;
+    let base = crate::qr::access_url(&server.config)
+        .ok_or(ApiError::Internal("no local address".into()))?;
+    let url = format!("{}{}/download/{}", base, config::API_V1_PATH, record.uuid);
+    let image = crate::qr::render(&url, format)?;
+    Ok((qr_content_type(format), image))
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct InfoResponse {
+    version: &'static str,
+    auth: bool,
+    /// uploads and deletes are disabled
+    read_only: bool,
+}
+
+/// Server version and enabled features
+#[openapi(tag = "Server")]
+#[get("/info")]
+async fn route_api_info(server: &State<SharedServer>) -> Json<InfoResponse> {
+    Json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        auth: server.runtime().admin_password.is_some(),
+        read_only: server.fm.is_read_only(),
+    })
+}
+
+/// All file records
+#[openapi(tag = "Files")]
+#[get("/list")]
+async fn route_api_list(server: &State<SharedServer>) -> Result<Json<Vec<Record>>, ApiError> {
+    let list = server.fm.get_all_records().await?;
+    Ok(Json(list))
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct UploadResponse {
     id: Uuid,
+    /// hex encoded SHA-256 of the stored content
+    sha256: String,
+}
+
+#[derive(rocket::Responder)]
+struct UploadReply {
+    inner: Json<UploadResponse>,
+    content_digest: Header<'static>,
 }
 
-#[rocket::post("/api/upload?<author>&<description>&<filename>", data = "<data>")]
+impl OpenApiResponderInner for UploadReply {
+    fn responses(generator: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        Json::<UploadResponse>::responses(generator)
+    }
+}
+
+/// Stores the request body as a new file.
+/// When the client sends the digest it expects, the content is verified against it.
+/// With `e2e` the body was encrypted by the client and is stored as it is,
+/// digests then cover the ciphertext.
+#[openapi(tag = "Files")]
+#[post("/upload?<author>&<description>&<filename>&<sha256>&<e2e>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
 async fn route_api_upload(
-    server: &State<Mutex<Server>>,
+    server: &State<SharedServer>,
+    transfers: &State<TransferTracker>,
+    client: ClientIp,
+    digest_headers: DigestHeaders,
     author: String,
     description: Option<String>,
     filename: String,
+    sha256: Option<&str>,
+    e2e: Option<bool>,
     data: Data<'_>,
-) -> Result<Json<UploadResponse>, status::Custom<&'static str>> {
+) -> Result<UploadReply, ApiError> {
+    if server.fm.is_read_only() {
+        return Err(ApiError::ReadOnly);
+    }
+    let from_query = sha256.map(Sha256Digest::from_hex).transpose();
+    let expected = match (from_query, digest_headers.expected()) {
+        (Ok(Some(query)), Ok(Some(header))) if query != header => {
+            Err("sha256 parameter and digest header disagree".to_string())
+        }
+        (Ok(Some(query)), Ok(_)) => Ok(Some(query)),
+        (Ok(None), header) => header,
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+    .map_err(ApiError::InvalidDigest)?;
+    let filename = crate::filename::sanitize(&filename).map_err(ApiError::InvalidFilename)?;
+
     let uuid = uuid::Uuid::new_v4();
+    let e2e = e2e.unwrap_or(false);
+    // ciphertext does not compress
+    let level = compression::level_for(&server.config.storage.compression, &filename)
+        .filter(|_| !e2e);
+    let key = server.key.as_deref().filter(|_| server.config.encryption.enabled);
+    let max_upload_size = server.runtime().limits.max_upload_size;
+    let transfer = transfers.start(client.0)?;
+    // written to the staging directory and handed to the storage once complete,
+    // so the storage never holds a half-written file
+    let staging_dir = server.staging_dir();
+    let partial = PartialUpload::new(staging_dir.join(uuid.to_string()));
+    log::info!("/api/upload: writing file at: {}", partial.path.display());
+    tokio::fs::create_dir_all(&staging_dir).await?;
+    let file = File::create(&partial.path).await?;
+    let stored = file.try_clone().await?;
+    // hashed before compression and on the way to disk, so the content is read only once
+    let mut writer = Tracked::new(
+        HashingWriter::new(compression::writer(crypto::writer(file, key), level)),
+        transfer,
+    );
+    let written = data
+        .open(max_upload_size)
+        .stream_to(&mut writer)
+        .await?;
+    // counts as a transfer until the file is stored
+    let (writer, _transfer) = writer.into_parts();
+    if !written.complete {
+        log::error!("/api/upload: incomplete file upload, aborting.");
+        return Err(ApiError::PayloadTooLarge {
+            limit: max_upload_size.as_u64(),
+        });
+    }
+    let (mut encoded, digest) = writer.finish();
+    // ends the compressed stream and seals the last chunk
+    encoded.shutdown().await?;
+    stored.sync_all().await?;
+    let stored_size = stored.metadata().await?.len();
+    if level.is_some() {
+        log::info!(
+            "/api/upload: compressed {} bytes to {}",
+            written.written,
+            stored_size
+        );
+    }
+    if let Some(expected) = expected
+        && expected != digest
+    {
+        log::warn!("/api/upload: digest mismatch, expected {}, received {}", expected, digest);
+        return Err(ApiError::DigestMismatch {
+            expected: expected.to_hex(),
+            actual: digest.to_hex(),
+        });
+    }
     let record = Record {
-        uuid: uuid.clone(),
+        uuid,
         uploaded_at: Utc::now(),
         name: filename,
-        description: description,
-        author: author,
+        description,
+        author,
+        sha256: Some(digest.to_hex()),
+        size: Some(written.written),
+        stored_size: Some(stored_size),
+        encoding: level.map(|_| compression::ZSTD.to_string()),
+        encrypted: key.is_some(),
+        e2e,
     };
-    let uploads_dir: PathBuf = {
-        let server_locked = server.lock().await;
-        let p: PathBuf = server_locked.config.path.uploads.clone().into();
-        server_locked.wd.join(p)
-    };
-    let p = uploads_dir.join(uuid.to_string());
-    log::info!("/api/upload: writing file at: {}", p.display());
-    let file = data.open(4.gibibytes()).into_file(&p).await.map_err(|e| {
-        log::error!("/api/upload: file write failed: {}", e);
-        status::Custom(Status::InternalServerError, "io error")
-    })?;
-    if !file.is_complete() {
-        log::error!("/api/upload: incomplete file upload, aborting.");
-        return Err(Custom(Status::InsufficientStorage, "too large file"));
-    }
-    {
-        let mut server_locked = server.lock().await;
-        match server_locked.fm.insert_record(record) {
-            Ok(_) => Ok(Json(UploadResponse { id: uuid })),
+    // recorded first and hidden until the content is stored, so no request sees a
+    // record without its content and a crash in between leaves a pending record
+    // that the next start removes with its content, see `Server::clean_pending`.
+    // Runs to the end even if the request is dropped meanwhile.
+    let staged = partial.keep();
+    let (storage, fm) = (server.storage.clone(), server.fm.clone());
+    tokio::spawn(async move {
+        let key = uuid.to_string();
+        if let Err(e) = fm.insert_pending_record(record).await {
+            let _ = tokio::fs::remove_file(&staged).await;
+            return Err(e.into());
+        }
+        let stored = match storage.put_file(&key, &staged).await {
+            Ok(_) => fm.commit_record(uuid).await.map(|_| ()).map_err(ApiError::from),
             Err(e) => {
-                log::error!("/api/upload: db write failed: {}", e);
-                Err(status::Custom(
-                    Status::InternalServerError,
-                    "db write failed",
-                ))
+                let _ = tokio::fs::remove_file(&staged).await;
+                Err(ApiError::Io(e))
+            }
+        };
+        if stored.is_err() {
+            // left pending for the next start when this fails as well
+            match storage.delete(&key).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    log::error!("/api/upload: could not remove unrecorded {}: {}", key, e);
+                }
+                _ => {
+                    if let Err(e) = fm.delete_record(uuid).await {
+                        log::error!("/api/upload: could not remove pending record {}: {}", key, e);
+                    }
+                }
             }
         }
+        stored
+    })
+    .await
+    .map_err(|e| ApiError::Internal(format!("upload task failed: {}", e)))??;
+    Ok(UploadReply {
+        inner: Json(UploadResponse {
+            id: uuid,
+            sha256: digest.to_hex(),
+        }),
+        content_digest: Header::new("Content-Digest", digest.header_value()),
+    })
+}
+
+/// Upload file that is removed again unless it is kept.
+/// Covers failed uploads as well as uploads cut off by shutdown,
+/// where the route future is dropped mid-write.
+struct PartialUpload {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl PartialUpload {
+    fn new(path: PathBuf) -> Self {
+        Self { path, keep: false }
+    }
+    /// Leaves the file for the caller to move or remove
+    fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => log::info!("/api/upload: removed partial file {}", self.path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!(
+                "/api/upload: could not remove partial file {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
     }
 }
 
 struct DownloadResponse {
     filename: String,
-    stream: ReaderStream<One<File>>,
+    sha256: Option<Sha256Digest>,
+    /// the part being sent and the size of the whole file, for range requests
+    range: Option<(std::ops::Range<u64>, u64)>,
+    /// `Content-Encoding` of content sent as stored
+    content_encoding: Option<&'static str>,
+    /// the file is stored compressed, so the response depends on `Accept-Encoding`
+    negotiated: bool,
+    stream: ReaderStream<One<Tracked<BlobReader>>>,
+}
+impl OpenApiResponderInner for DownloadResponse {
+    fn responses(_generator: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let binary = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("binary".to_string()),
+            ..Default::default()
+        };
+        add_schema_response(&mut responses, 200, ContentType::Binary.to_string(), binary.clone())?;
+        add_schema_response(&mut responses, 206, ContentType::Binary.to_string(), binary)?;
+        Ok(responses)
+    }
 }
+
 impl<'r, 'o: 'r> Responder<'r, 'o> for DownloadResponse {
     fn respond_to(self, _request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
-        Response::build()
+        let mut response = Response::build();
+        response
             .header(ContentType::Binary)
+            .raw_header(header::ACCEPT_RANGES.as_str(), "bytes")
             .raw_header(
                 header::CONTENT_DISPOSITION.as_str(),
-                format!("attachment; filename=\"{}\"", self.filename),
-            )
-            .streamed_body(self.stream)
-            .ok()
+                crate::filename::content_disposition(&self.filename),
+            );
+        if self.negotiated {
+            response.raw_header(header::VARY.as_str(), "Accept-Encoding");
+        }
+        if let Some(encoding) = self.content_encoding {
+            response.raw_header(header::CONTENT_ENCODING.as_str(), encoding);
+        }
+        match self.range {
+            Some((range, size)) => {
+                response.status(Status::PartialContent).raw_header(
+                    header::CONTENT_RANGE.as_str(),
+                    crate::range::content_range(&range, size),
+                );
+            }
+            // the digest covers the whole content only
+            None => {
+                if let Some(sha256) = self.sha256 {
+                    response.raw_header("Content-Digest", sha256.header_value());
+                }
+            }
+        }
+        response.streamed_body(self.stream).ok()
     }
 }
 
-#[rocket::get("/api/download/<file_uuid>")]
+/// Streams the content of a file as an attachment, or the requested range of it
+#[openapi(tag = "Files")]
+#[get("/download/<file_uuid>")]
 async fn route_api_download(
-    server: &State<Mutex<Server>>,
+    server: &State<SharedServer>,
+    transfers: &State<TransferTracker>,
+    client: ClientIp,
+    range: RangeHeader,
+    accept_encoding: AcceptEncoding,
     file_uuid: Uuid,
-) -> Result<DownloadResponse, Custom<&str>> {
-    let record = {
-        server
-            .lock()
-            .await
-            .fm
-            .get_record_by_uuid(file_uuid)
-            .map_err(|e| {
-                log::error!("{}", e);
-                Custom(Status::InternalServerError, "db query failed")
-            })?
-            .ok_or(Custom(Status::NotFound, "file record not found"))?
-    };
+) -> Result<DownloadResponse, ApiError> {
+    let record = server
+        .fm
+        .get_record_by_uuid(file_uuid)
+        .await?
+        .ok_or(ApiError::NotFound("file record"))?;
+    let key = server
+        .fm
+        .blob_key(file_uuid)
+        .ok_or(ApiError::NotFound("file record"))?;
+    let transfer = transfers.start(client.0)?;
 
-    let uploads_dir: PathBuf = {
-        let server = server.lock().await;
-        server.wd.join(server.config.path.uploads.clone())
+    let Some(blob) = server.storage.stat(&key).await? else {
+        log::error!("/api/download : {} is missing from the storage", key);
+        return Err(ApiError::FileMissing);
     };
-
-    let file_path = uploads_dir.join(record.uuid.to_string());
-    let file = File::open(&file_path).await.map_err(|e| {
+    let compressed = record.encoding.as_deref() == Some(compression::ZSTD);
+    // ranges are of the original content
+    let size = match record.size {
+        Some(size) if compressed || record.encrypted => size,
+        _ if record.encrypted => crypto::content_size(blob.size)?,
+        _ => blob.size,
+    };
+    let range = match range.resolve(size) {
+        Requested::Full => None,
+        Requested::Partial(range) => Some(range),
+        Requested::Unsatisfiable => return Err(ApiError::RangeNotSatisfiable { size }),
+    };
+    let passthrough = compressed && range.is_none() && accept_encoding.zstd();
+    // compressed content is decompressed from its start
+    let stored_range = if compressed { None } else { range.clone() };
+    let stored = if record.encrypted {
+        let master = server.key.as_deref().ok_or(ApiError::KeyUnavailable)?;
+        crypto::open(&*server.storage, &key, master, blob.size, stored_range).await
+    } else {
+        server.storage.get(&key, stored_range).await
+    };
+    let stored = stored.map_err(|e| {
         log::error!("/api/download : {}", e);
-        Custom(Status::NotFound, "could not open requested file")
+        match e.kind() {
+            std::io::ErrorKind::NotFound => ApiError::FileMissing,
+            _ => ApiError::Io(e),
+        }
     })?;
-    let stream = ReaderStream::one(file);
+    let reader = if compressed && !passthrough {
+        compression::decode(stored, range.clone()).await?
+    } else {
+        stored
+    };
+    let stream = ReaderStream::one(Tracked::new(reader, transfer));
     Ok(DownloadResponse {
+        // a malformed stored checksum only costs the client the header,
+        // and it does not describe compressed content
+        sha256: record
+            .sha256
+            .as_deref()
+            .filter(|_| !passthrough)
+            .and_then(|hex| Sha256Digest::from_hex(hex).ok()),
         filename: record.name,
+        range: range.map(|range| (range, size)),
+        content_encoding: passthrough.then_some(compression::ZSTD),
+        negotiated: compressed,
         stream,
     })
 }
 
-#[rocket::delete("/api/delete/<file_uuid>")]
+/// Deletes a file and its record
+#[openapi(tag = "Files")]
+#[delete("/delete/<file_uuid>")]
 async fn route_api_delete(
-    server: &State<Mutex<Server>>,
+    server: &State<SharedServer>,
     _session: SessionId,
     file_uuid: Uuid,
-) -> Result<Status, status::Custom<&'static str>> {
+) -> Result<NoContent, ApiError> {
+    if server.fm.is_read_only() {
+        return Err(ApiError::ReadOnly);
+    }
     // get requested record meta
-    let record = {
-        server
-            .lock()
-            .await
-            .fm
-            .get_record_by_uuid(file_uuid)
-            .map_err(|e| {
-                log::error!("FmError: {}", e);
-                Custom(Status::InternalServerError, "database query failed")
-            })?
-            .ok_or(Custom(Status::NotFound, "file record not found"))?
-    };
-    let uploads_dir: PathBuf = {
-        let server = server.lock().await;
-        server.wd.join(server.config.path.uploads.clone())
-    };
-    tokio::fs::remove_file(uploads_dir.join(record.uuid.to_string()))
+    let record = server
+        .fm
+        .get_record_by_uuid(file_uuid)
+        .await?
+        .ok_or(ApiError::NotFound("file record"))?;
+    server
+        .storage
+        .delete(&record.uuid.to_string())
         .await
         .map_err(|e| {
             if matches!(e.kind(), std::io::ErrorKind::NotFound) {
                 log::warn!(
-                    "/api/delete: requested record doesnt exist in disk, but exists in database"
+                    "/api/delete: requested record doesnt exist in storage, but exists in database"
                 );
+                return ApiError::FileMissing;
             }
-            Custom(Status::InternalServerError, "file removal failed")
+            ApiError::Io(e)
         })?;
-    server
-        .lock()
-        .await
-        .fm
-        .delete_record(file_uuid)
-        .map_err(|_| Custom(Status::InternalServerError, "db delete failed"))?;
-    Ok(Status::NoContent)
+    server.fm.delete_record(file_uuid).await?;
+    Ok(NoContent)
+}
+
+/// Transfer limits in bytes, `null` for no limit, see `[limits]` in the config
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct LimitsBody {
+    max_upload_size: u64,
+    /// bytes per second all uploads and downloads share
+    bandwidth: Option<u64>,
+    /// bytes per second the uploads and downloads of a single client share
+    client_bandwidth: Option<u64>,
+    /// uploads and downloads a single client may run at once
+    max_transfers_per_client: Option<usize>,
+}
+
+impl From<LimitsConfig> for LimitsBody {
+    fn from(limits: LimitsConfig) -> Self {
+        Self {
+            max_upload_size: limits.max_upload_size.as_u64(),
+            bandwidth: limits.bandwidth.map(|b| b.as_u64()),
+            client_bandwidth: limits.client_bandwidth.map(|b| b.as_u64()),
+            max_transfers_per_client: limits.max_transfers_per_client,
+        }
+    }
+}
+
+impl From<LimitsBody> for LimitsConfig {
+    fn from(body: LimitsBody) -> Self {
+        Self {
+            max_upload_size: body.max_upload_size.bytes(),
+            bandwidth: body.bandwidth.map(|b| b.bytes()),
+            client_bandwidth: body.client_bandwidth.map(|b| b.bytes()),
+            max_transfers_per_client: body.max_transfers_per_client,
+        }
+    }
+}
+
+/// Limits in effect
+#[openapi(tag = "Admin")]
+#[get("/admin/limits")]
+async fn route_api_get_limits(
+    server: &State<SharedServer>,
+    _session: SessionId,
+) -> Json<LimitsBody> {
+    Json(server.runtime().limits.into())
 }
 
-#[rocket::get("/api/login?<return_url>")]
+/// Replaces the limits, running transfers included.
+/// They last until the server restarts or LocalShare.toml changes.
+#[openapi(tag = "Admin")]
+#[put("/admin/limits", data = "<body>")]
+async fn route_api_set_limits(
+    server: &State<SharedServer>,
+    _session: SessionId,
+    body: Json<LimitsBody>,
+) -> Result<Json<LimitsBody>, ApiError> {
+    let limits = LimitsConfig::from(body.into_inner());
+    let problems = limits.problems();
+    if !problems.is_empty() {
+        return Err(ApiError::BadRequest(problems.join(", ")));
+    }
+    log::info!("/api/admin/limits: {:?}", limits);
+    server.set_limits(limits);
+    Ok(Json(server.runtime().limits.into()))
+}
+
+/// Redirects to `return_url` when already logged in or auth is disabled,
+/// to the login page otherwise
+#[openapi(tag = "Session")]
+#[get("/login?<return_url>")]
 async fn route_api_login(
-    server: &State<Mutex<Server>>,
-    session_storage: &State<Mutex<SessionStorage>>,
+    server: &State<SharedServer>,
+    session_storage: &State<SharedSessionStorage>,
     cookies: &CookieJar<'_>,
     return_url: Option<String>,
 ) -> Redirect {
@@ -332,14 +1078,12 @@ async fn route_api_login(
         return Redirect::to(return_to);
     }
 
-    let auth_enabled = {
-        server.lock().await.admin_password.is_some()
-    };
+    let auth_enabled = server.runtime().admin_password.is_some();
 
     if !auth_enabled {
         // Auth is off — auto-issue a session and grant access
         let session_id = SessionId::generate();
-        session_storage.lock().await.insert(session_id.clone());
+        session_storage.lock().await.insert(session_id);
         cookies.add_private(Cookie::from(session_id));
         return Redirect::to(return_to);
     }
@@ -348,29 +1092,30 @@ async fn route_api_login(
     Redirect::to(format!("/login?return_url={}", return_to))
 }
 
-#[rocket::get("/api/session")]
+/// Succeeds when the session cookie is valid
+#[openapi(tag = "Session")]
+#[get("/session")]
 async fn route_api_session(_session: SessionId) -> Status {
     Status::Ok
 }
 
 
-#[derive(FromForm)]
+#[derive(FromForm, JsonSchema)]
 struct LoginForm {
     password: String,
     from: Option<String>,
 }
 
-#[rocket::post("/api/auth", data = "<form>")]
+/// Logs in with the admin password and sets the session cookie
+#[openapi(tag = "Session")]
+#[post("/auth", data = "<form>")]
 async fn route_api_auth(
-    server: &State<Mutex<Server>>,
-    session_storage: &State<Mutex<SessionStorage>>,
+    server: &State<SharedServer>,
+    session_storage: &State<SharedSessionStorage>,
     cookies: &CookieJar<'_>,
     form: Form<LoginForm>,
-) -> Result<Redirect, status::Custom<&'static str>> {
-    let password_opt = {
-        let s = server.lock().await;
-        s.admin_password.clone()
-    };
+) -> Result<Redirect, ApiError> {
+    let password_opt = server.runtime().admin_password;
     let redirect_to = form.from.clone().unwrap_or_else(|| "/".into());
 
     match password_opt {
@@ -378,11 +1123,11 @@ async fn route_api_auth(
             // POTENTIAL VULNERABILITY: timing attack possible, fix later
             if password == form.password {
                 let session_id = SessionId::generate();
-                session_storage.lock().await.insert(session_id.clone());
+                session_storage.lock().await.insert(session_id);
                 cookies.add_private(Cookie::from(session_id));
                 Ok(Redirect::to(redirect_to))
             } else {
-                Err(Custom(Status::Unauthorized, "wrong password"))
+                Err(ApiError::WrongPassword)
             }
         }
         None => Ok(Redirect::to(redirect_to)),
@@ -390,10 +1135,12 @@ async fn route_api_auth(
 }
 
 
-#[rocket::post("/api/logout?<return_url>")]
+/// Ends the session
+#[openapi(tag = "Session")]
+#[post("/logout?<return_url>")]
 async fn route_api_logout(
     session_id : SessionId,
-    session_storage: &State<Mutex<SessionStorage>>,
+    session_storage: &State<SharedSessionStorage>,
     cookies: &CookieJar<'_>,
     return_url: Option<String>) -> Redirect {
         session_storage.lock().await.remove(&session_id);
@@ -6,7 +6,12 @@ use rocket::{
     http::{Cookie, Status},
     request::{FromRequest, Outcome},
 };
-use std::{collections::HashSet, hash::Hash, str::FromStr};
+use rocket_okapi::{
+    r#gen::OpenApiGenerator,
+    okapi::openapi3::{SecurityRequirement, SecurityScheme, SecuritySchemeData},
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use std::{collections::HashSet, hash::Hash, str::FromStr, sync::Arc};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
@@ -52,7 +57,7 @@ impl<'r> FromRequest<'r> for SessionId {
             Some(s) => {
                 let session_storage = request
                     .rocket()
-                    .state::<Mutex<SessionStorage>>()
+                    .state::<SharedSessionStorage>()
                     .expect("rocket manages session storage");
                 let session_id: SessionId = match s.parse() {
                     Ok(id) => id,
@@ -70,11 +75,45 @@ impl<'r> FromRequest<'r> for SessionId {
     }
 }
 
+/// Routes taking a [`SessionId`] need the session cookie issued by `/api/v1/auth`
+impl<'r> OpenApiFromRequest<'r> for SessionId {
+    fn from_request_input(
+        _generator: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        let scheme = SecurityScheme {
+            description: Some("Session cookie set by a successful login".to_string()),
+            data: SecuritySchemeData::ApiKey {
+                name: SESSION_COOKIE_NAME.to_string(),
+                location: "cookie".to_string(),
+            },
+            extensions: Default::default(),
+        };
+        let mut requirement = SecurityRequirement::new();
+        requirement.insert("session".to_string(), Vec::new());
+        Ok(RequestHeaderInput::Security(
+            "session".to_string(),
+            scheme,
+            requirement,
+        ))
+    }
+}
+
+/// Session storage shared by every listening socket
+pub type SharedSessionStorage = Arc<Mutex<SessionStorage>>;
+
 /// Stores the sessions
 pub struct SessionStorage {
     sessions: HashSet<SessionId>,
 }
 
+impl Default for SessionStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SessionStorage {
     pub fn new() -> Self {
         Self {
@@ -90,4 +129,7 @@ impl SessionStorage {
     pub fn remove(&mut self, session_id: &SessionId) {
         self.sessions.remove(session_id);
     }
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
 }
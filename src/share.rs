@@ -0,0 +1,139 @@
+//! This module serves an existing directory in place, see `share.directory`
+//!
+//! The directory is indexed in memory and rescanned periodically. Record ids are
+//! derived from the relative path, so download links stay valid across rescans
+//! and restarts for as long as the file is not moved.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{fm::record::Record, server::SharedServer};
+
+/// Author shown for files of a shared directory
+const SHARE_AUTHOR: &str = "localshare";
+
+/// Files of a shared directory, keyed by record id.
+/// Each record comes with its storage key, the path relative to the directory.
+#[derive(Debug, Default)]
+pub struct SharedIndex {
+    files: HashMap<Uuid, (Record, String)>,
+}
+
+impl SharedIndex {
+    /// Walks `root` and indexes every regular file below it. Blocking.
+    /// Hidden files and directories, as well as symbolic links, are skipped.
+    pub fn scan(root: &Path) -> anyhow::Result<Self> {
+        let mut files = HashMap::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries =
+                std::fs::read_dir(&dir).context(format!("Could not read {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let path = entry.path();
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    pending.push(path);
+                } else if meta.is_file() {
+                    let relative = path.strip_prefix(root).unwrap_or(&path);
+                    let key = relative.to_string_lossy().replace('\\', "/");
+                    let record = record_for(&key, meta.modified()?.into(), meta.len());
+                    files.insert(record.uuid, (record, key));
+                }
+            }
+        }
+        Ok(Self { files })
+    }
+
+    /// Records sorted by path
+    pub fn records(&self) -> Vec<Record> {
+        let mut files: Vec<_> = self.files.values().collect();
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+        files.into_iter().map(|(record, _)| record.clone()).collect()
+    }
+
+    pub fn get(&self, uuid: Uuid) -> Option<&Record> {
+        self.files.get(&uuid).map(|(record, _)| record)
+    }
+
+    pub fn key(&self, uuid: Uuid) -> Option<&str> {
+        self.files.get(&uuid).map(|(_, key)| key.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Number of files added and removed compared to `old`
+    pub fn diff(&self, old: &Self) -> (usize, usize) {
+        let added = self.files.keys().filter(|k| !old.files.contains_key(k)).count();
+        let removed = old.files.keys().filter(|k| !self.files.contains_key(k)).count();
+        (added, removed)
+    }
+}
+
+fn record_for(relative: &str, modified: DateTime<Utc>, size: u64) -> Record {
+    let (folder, name) = match relative.rsplit_once('/') {
+        Some((folder, name)) => (Some(folder.to_string()), name.to_string()),
+        None => (None, relative.to_string()),
+    };
+    Record {
+        uuid: Uuid::new_v5(&Uuid::NAMESPACE_URL, relative.as_bytes()),
+        uploaded_at: modified,
+        name,
+        // the folder is all that tells apart files of the same name
+        description: folder,
+        author: SHARE_AUTHOR.to_string(),
+        sha256: None,
+        size: Some(size),
+        stored_size: Some(size),
+        encoding: None,
+        encrypted: false,
+        e2e: false,
+    }
+}
+
+/// Rescans the shared directory every `share.rescan_interval` seconds.
+/// Runs until the task is aborted; does nothing when no directory is shared.
+pub async fn watch(server: SharedServer) {
+    let (root, interval) = match server.shared_dir() {
+        Some(root) => (root, server.rescan_interval()),
+        None => return,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(interval.into()));
+    // the index was built on startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let scan_root = root.clone();
+        let index = match tokio::task::spawn_blocking(move || SharedIndex::scan(&scan_root)).await {
+            Ok(Ok(index)) => index,
+            Ok(Err(e)) => {
+                log::error!("share: rescan of {} failed: {:#}", root.display(), e);
+                continue;
+            }
+            Err(e) => {
+                log::error!("share: rescan of {} failed: {}", root.display(), e);
+                continue;
+            }
+        };
+        let (added, removed) = server.replace_shared_index(index);
+        if added > 0 || removed > 0 {
+            log::info!("share: {} file(s) added, {} removed", added, removed);
+        }
+    }
+}
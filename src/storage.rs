@@ -0,0 +1,85 @@
+//! This module keeps the content of files, see `storage.backend`
+//!
+//! Records live in the database, their content in a [`Storage`] under a key,
+//! the record uuid for uploads. Uploads are still received into the local
+//! staging directory, and only handed to the storage once they are verified.
+
+mod local;
+mod s3;
+
+use std::{
+    io,
+    ops::Range,
+    path::{Component, Path},
+    pin::Pin,
+    sync::Arc,
+};
+
+use tokio::io::AsyncRead;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+use crate::config::{Config, StorageBackend};
+
+/// Content of a blob, or of the requested range of it
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Metadata of a stored blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobInfo {
+    pub size: u64,
+}
+
+/// Where the content of files is kept.
+/// Missing blobs are reported as [`io::ErrorKind::NotFound`].
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores everything `data` yields under `key`, replacing an existing blob.
+    /// Returns the number of bytes stored.
+    async fn put(&self, key: &str, data: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<u64>;
+
+    /// Moves the finished file at `path` into the storage under `key`.
+    /// Uploads it by default, backends on the same file system rename it instead.
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<u64> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = self.put(key, &mut file).await?;
+        tokio::fs::remove_file(path).await?;
+        Ok(size)
+    }
+
+    /// Reads the blob stored under `key`, or only `range` of it
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<BlobReader>;
+
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// `None` if nothing is stored under `key`
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobInfo>>;
+
+    /// Keys of every stored blob
+    async fn list(&self) -> io::Result<Vec<String>>;
+}
+
+/// The storage `config` asks for
+pub fn from_config(workdir: &Path, config: &Config) -> anyhow::Result<Arc<dyn Storage>> {
+    Ok(match config.storage.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(workdir.join(&config.path.uploads))),
+        StorageBackend::S3 => Arc::new(S3Storage::new(&config.storage.s3)?),
+    })
+}
+
+/// Keys are relative paths that must not leave the storage
+fn check_key(key: &str) -> io::Result<()> {
+    let path = Path::new(key);
+    let valid = !key.is_empty()
+        && !key.contains('\\')
+        && path.components().all(|c| matches!(c, Component::Normal(_)));
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid storage key '{}'", key.escape_debug()),
+        ))
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::{BlobInfo, BlobReader, Storage, check_key};
+
+/// Blobs as files below a directory, the default
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &mut (dyn tokio::io::AsyncRead + Send + Unpin)) -> io::Result<u64> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = File::create(&path).await?;
+        let size = tokio::io::copy(data, &mut file).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(size)
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<u64> {
+        let target = self.path(key)?;
+        let size = tokio::fs::metadata(path).await?.len();
+        // staged within the same file system, see `config::STAGING_DIR`
+        tokio::fs::rename(path, &target).await?;
+        sync_dir(&self.root).await;
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<BlobReader> {
+        let mut file = File::open(self.path(key)?).await?;
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(Box::pin(file.take(range.end - range.start)))
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        tokio::fs::remove_file(self.path(key)?).await
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobInfo>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(meta) if meta.is_file() => Ok(Some(BlobInfo { size: meta.len() })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // the staging directory and the like
+            if name.starts_with('.') || !entry.file_type().await?.is_file() {
+                continue;
+            }
+            keys.push(name);
+        }
+        Ok(keys)
+    }
+}
+
+/// Makes a rename into `dir` durable. Best effort, failures are only logged.
+async fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Err(e) = async { File::open(dir).await?.sync_all().await }.await {
+        log::warn!("could not sync {}: {}", dir.display(), e);
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
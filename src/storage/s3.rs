@@ -0,0 +1,176 @@
+use std::{
+    io,
+    ops::Range,
+    pin::Pin,
+    task::{Context as TaskContext, Poll, ready},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use s3::{Bucket, Region, creds::Credentials, error::S3Error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_util::{io::StreamReader, sync::PollSender};
+
+use super::{BlobInfo, BlobReader, Storage, check_key};
+use crate::config::S3Config;
+
+/// Chunks buffered between the object store and the client of a download
+const PIPE_CHUNKS: usize = 32;
+
+/// Blobs as objects in an S3 compatible bucket
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Storage {
+    /// Reads the credentials from the environment, see [`S3Config`]
+    pub fn new(config: &S3Config) -> anyhow::Result<Self> {
+        let access_key = std::env::var("LOCALSHARE_S3_ACCESS_KEY")
+            .context("LOCALSHARE_S3_ACCESS_KEY must be set for the s3 storage backend")?;
+        let secret_key = std::env::var("LOCALSHARE_S3_SECRET_KEY")
+            .context("LOCALSHARE_S3_SECRET_KEY must be set for the s3 storage backend")?;
+        Self::with_credentials(config, &access_key, &secret_key)
+    }
+
+    pub fn with_credentials(
+        config: &S3Config,
+        access_key: &str,
+        secret_key: &str,
+    ) -> anyhow::Result<Self> {
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)?;
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.trim_end_matches('/').to_string(),
+        };
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)
+            .context(format!("Invalid bucket '{}'", config.bucket))?;
+        if config.path_style {
+            bucket.set_path_style();
+        }
+        Ok(Self {
+            bucket,
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    fn object(&self, key: &str) -> io::Result<String> {
+        check_key(key)?;
+        Ok(format!("{}{}", self.prefix, key))
+    }
+}
+
+fn to_io(e: S3Error) -> io::Error {
+    match e {
+        S3Error::HttpFailWithBody(404, _) => io::Error::new(io::ErrorKind::NotFound, "no such object"),
+        S3Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<u64> {
+        let response = self
+            .bucket
+            .put_object_stream(data, self.object(key)?)
+            .await
+            .map_err(to_io)?;
+        Ok(response.uploaded_bytes() as u64)
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<BlobReader> {
+        let object = self.object(key)?;
+        // fails early on a missing object, rather than with an empty body
+        if range.is_none() {
+            self.bucket.head_object(&object).await.map_err(to_io)?;
+        }
+        let (sender, mut receiver) = mpsc::channel::<io::Result<Bytes>>(PIPE_CHUNKS);
+        let failed = sender.clone();
+        let mut writer = ChannelWriter(PollSender::new(sender));
+        let bucket = self.bucket.clone();
+        tokio::spawn(async move {
+            let result = match range {
+                // an empty range would read the whole object
+                Some(range) if range.is_empty() => Ok(200),
+                // S3 ranges include their end
+                Some(range) => {
+                    bucket
+                        .get_object_range_to_writer(&object, range.start, Some(range.end - 1), &mut writer)
+                        .await
+                }
+                None => bucket.get_object_to_writer(&object, &mut writer).await,
+            };
+            // queued after the chunks received so far, so the client sees the body
+            // fail instead of ending early
+            if let Err(e) = result {
+                log::error!("s3: reading {} failed: {}", object, e);
+                let _ = failed.send(Err(to_io(e))).await;
+            }
+        });
+        let chunks = rocket::futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        Ok(Box::pin(StreamReader::new(chunks)))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let object = self.object(key)?;
+        // deleting a missing object succeeds in S3
+        self.bucket.head_object(&object).await.map_err(to_io)?;
+        self.bucket.delete_object(&object).await.map_err(to_io)?;
+        Ok(())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobInfo>> {
+        match self.bucket.head_object(self.object(key)?).await {
+            Ok((head, _)) => Ok(Some(BlobInfo {
+                size: head.content_length.unwrap_or_default().max(0) as u64,
+            })),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(to_io(e)),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let pages = self
+            .bucket
+            .list(self.prefix.clone(), None)
+            .await
+            .map_err(to_io)?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| object.key.strip_prefix(&self.prefix).map(str::to_string))
+            .collect())
+    }
+}
+
+/// Sends everything written to it down a channel, one chunk per write
+struct ChannelWriter(PollSender<io::Result<Bytes>>);
+
+impl AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let sender = &mut self.get_mut().0;
+        // the reader is gone
+        let closed = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+        ready!(sender.poll_reserve(cx)).map_err(closed)?;
+        sender
+            .send_item(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(closed)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
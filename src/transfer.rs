@@ -0,0 +1,400 @@
+//! This module keeps track of uploads and downloads in flight
+//!
+//! Every transfer belongs to a client IP, which may only run
+//! `limits.max_transfers_per_client` of them at once. Transfers are paced to
+//! `limits.bandwidth` together and to `limits.client_bandwidth` per client:
+//! each chunk is charged to a token bucket, and the transfer waits out any
+//! debt before it moves the next one. Limits apply to running transfers as
+//! soon as they change.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::{config::LimitsConfig, error::ApiError};
+
+/// Unused bandwidth saved up by an idle bucket, so short bursts go out at full speed
+const BURST: Duration = Duration::from_millis(250);
+
+/// Counts and paces active transfers, shared by every listening socket
+#[derive(Debug, Clone)]
+pub struct TransferTracker(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    active: AtomicUsize,
+    limits: RwLock<LimitsConfig>,
+    global: Mutex<Bucket>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+/// Transfers of one IP, forgotten once it has none left
+#[derive(Debug)]
+struct Client {
+    active: usize,
+    bucket: Bucket,
+}
+
+impl TransferTracker {
+    pub fn new(limits: LimitsConfig) -> Self {
+        Self(Arc::new(Inner {
+            active: AtomicUsize::new(0),
+            limits: RwLock::new(limits),
+            global: Mutex::new(Bucket::new()),
+            clients: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Applies new limits, running transfers included
+    pub fn set_limits(&self, limits: LimitsConfig) {
+        *self.0.limits.write().unwrap() = limits;
+    }
+
+    /// Marks a transfer of `client` as started until the returned guard is dropped.
+    /// Fails when the client already runs as many transfers as it may.
+    pub fn start(&self, client: IpAddr) -> Result<TransferGuard, ApiError> {
+        let max = self.0.limits.read().unwrap().max_transfers_per_client;
+        let mut clients = self.0.clients.lock().unwrap();
+        let entry = clients.entry(client).or_insert_with(|| Client {
+            active: 0,
+            bucket: Bucket::new(),
+        });
+        if let Some(limit) = max
+            && entry.active >= limit
+        {
+            log::info!("transfer: {} already runs {} transfer(s)", client, entry.active);
+            return Err(ApiError::TooManyTransfers { limit });
+        }
+        entry.active += 1;
+        self.0.active.fetch_add(1, Ordering::SeqCst);
+        Ok(TransferGuard {
+            inner: self.0.clone(),
+            client,
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.0.active.load(Ordering::SeqCst)
+    }
+}
+
+/// A transfer in flight, finished when dropped
+#[derive(Debug)]
+pub struct TransferGuard {
+    inner: Arc<Inner>,
+    client: IpAddr,
+}
+
+impl TransferGuard {
+    /// Charges `bytes` moved by this transfer, returns how long it has to wait
+    /// before moving more
+    fn charge(&self, bytes: usize) -> Duration {
+        let limits = self.inner.limits.read().unwrap();
+        let now = Instant::now();
+        let mut delay = Duration::ZERO;
+        if let Some(rate) = limits.bandwidth {
+            delay = self.inner.global.lock().unwrap().take(bytes, rate.as_u64(), now);
+        }
+        if let Some(rate) = limits.client_bandwidth
+            && let Some(client) = self.inner.clients.lock().unwrap().get_mut(&self.client)
+        {
+            delay = delay.max(client.bucket.take(bytes, rate.as_u64(), now));
+        }
+        delay
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        let mut clients = self.inner.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&self.client) {
+            client.active -= 1;
+            if client.active == 0 {
+                clients.remove(&self.client);
+            }
+        }
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Bandwidth as a token bucket that can go into debt
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` at `rate` bytes per second, returns how long until the debt is paid
+    fn take(&mut self, bytes: usize, rate: u64, now: Instant) -> Duration {
+        let rate = rate.max(1) as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate * BURST.as_secs_f64());
+        self.updated = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Reader or writer that counts as an active transfer for as long as it lives,
+/// and is paced to the bandwidth limits. Download bodies are streamed after
+/// the route returns, so the guard has to travel with the reader.
+pub struct Tracked<T> {
+    inner: T,
+    guard: TransferGuard,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Tracked<T> {
+    pub fn new(inner: T, guard: TransferGuard) -> Self {
+        Self {
+            inner,
+            guard,
+            wait: None,
+        }
+    }
+
+    /// Stops pacing, the transfer lasts until the guard is dropped
+    pub fn into_parts(self) -> (T, TransferGuard) {
+        (self.inner, self.guard)
+    }
+
+    /// Waits out the debt of earlier chunks
+    fn poll_wait(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(wait) = &mut self.wait {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        let delay = self.guard.charge(bytes);
+        if !delay.is_zero() {
+            self.wait = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_wait(cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.charge(buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_wait(cx));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.charge(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use rocket::data::ToByteUnit;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 11));
+
+    fn limits(
+        bandwidth: Option<u64>,
+        client_bandwidth: Option<u64>,
+        max_transfers_per_client: Option<usize>,
+    ) -> LimitsConfig {
+        LimitsConfig {
+            bandwidth: bandwidth.map(|b| b.bytes()),
+            client_bandwidth: client_bandwidth.map(|b| b.bytes()),
+            max_transfers_per_client,
+            ..Default::default()
+        }
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    /// Buckets charged through a guard refill for the time the test took so far
+    fn assert_close(actual: Duration, expected: Duration) {
+        let diff = actual.abs_diff(expected);
+        assert!(diff < Duration::from_millis(20), "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn new_bucket_has_no_tokens() {
+        let mut bucket = Bucket::new();
+        let now = bucket.updated;
+        assert_close(bucket.take(1000, 1000, now), secs(1.0));
+    }
+
+    #[test]
+    fn debt_adds_up() {
+        let mut bucket = Bucket::new();
+        let now = bucket.updated;
+        assert_close(bucket.take(500, 1000, now), secs(0.5));
+        assert_close(bucket.take(500, 1000, now), secs(1.0));
+        assert_close(bucket.take(0, 1000, now), secs(1.0));
+    }
+
+    #[test]
+    fn debt_is_paid_back_over_time() {
+        let mut bucket = Bucket::new();
+        let start = bucket.updated;
+        assert_close(bucket.take(2000, 1000, start), secs(2.0));
+        assert_close(bucket.take(0, 1000, start + secs(1.5)), secs(0.5));
+        assert_eq!(bucket.take(0, 1000, start + secs(2.0)), Duration::ZERO);
+        assert_close(bucket.take(100, 1000, start + secs(2.0)), secs(0.1));
+    }
+
+    #[test]
+    fn idle_time_saves_up_to_the_burst() {
+        let mut bucket = Bucket::new();
+        let later = bucket.updated + Duration::from_secs(60);
+        // a quarter of a second at 1000 bytes per second, not a minute's worth
+        assert_eq!(bucket.take(250, 1000, later), Duration::ZERO);
+        assert_close(bucket.take(100, 1000, later), secs(0.1));
+    }
+
+    #[test]
+    fn zero_rate_counts_as_one_byte_per_second() {
+        let mut bucket = Bucket::new();
+        let now = bucket.updated;
+        assert_close(bucket.take(3, 0, now), secs(3.0));
+    }
+
+    #[test]
+    fn transfers_per_client_are_capped() {
+        let tracker = TransferTracker::new(limits(None, None, Some(2)));
+        let first = tracker.start(A).unwrap();
+        let _second = tracker.start(A).unwrap();
+        assert!(matches!(
+            tracker.start(A),
+            Err(ApiError::TooManyTransfers { limit: 2 })
+        ));
+        // other clients have their own count
+        let _other = tracker.start(B).unwrap();
+        assert_eq!(tracker.active(), 3);
+
+        drop(first);
+        assert_eq!(tracker.active(), 2);
+        let _third = tracker.start(A).unwrap();
+        assert!(tracker.start(A).is_err());
+    }
+
+    #[test]
+    fn clients_are_forgotten_without_transfers() {
+        let tracker = TransferTracker::new(limits(None, None, Some(1)));
+        let guard = tracker.start(A).unwrap();
+        assert!(tracker.0.clients.lock().unwrap().contains_key(&A));
+        drop(guard);
+        assert!(tracker.0.clients.lock().unwrap().is_empty());
+        assert_eq!(tracker.active(), 0);
+        tracker.start(A).unwrap();
+    }
+
+    #[test]
+    fn guards_dropped_with_their_reader_free_the_slot() {
+        let tracker = TransferTracker::new(limits(None, None, Some(1)));
+        let reader = Tracked::new(&b"content"[..], tracker.start(A).unwrap());
+        assert!(tracker.start(A).is_err());
+        drop(reader);
+        assert_eq!(tracker.active(), 0);
+        assert!(tracker.start(A).is_ok());
+    }
+
+    #[test]
+    fn new_limits_apply_to_running_transfers() {
+        let tracker = TransferTracker::new(limits(None, None, None));
+        let guard = tracker.start(A).unwrap();
+        assert_eq!(guard.charge(1_000_000), Duration::ZERO);
+
+        tracker.set_limits(limits(Some(1000), None, Some(1)));
+        assert_close(guard.charge(500), secs(0.5));
+        assert!(tracker.start(A).is_err());
+    }
+
+    #[test]
+    fn clients_share_the_global_bucket() {
+        let tracker = TransferTracker::new(limits(Some(1000), Some(10_000), None));
+        let a = tracker.start(A).unwrap();
+        let b = tracker.start(B).unwrap();
+        assert_close(a.charge(100), secs(0.1));
+        // B's own bucket is nearly full, the shared one already owes A's bytes
+        assert_close(b.charge(100), secs(0.2));
+    }
+
+    #[test]
+    fn the_client_bucket_can_be_the_slower_one() {
+        let tracker = TransferTracker::new(limits(Some(10_000), Some(1000), None));
+        let a = tracker.start(A).unwrap();
+        let b = tracker.start(B).unwrap();
+        assert_close(a.charge(1000), secs(1.0));
+        assert_close(b.charge(1000), secs(1.0));
+    }
+
+    #[tokio::test]
+    async fn writes_are_paced() {
+        let tracker = TransferTracker::new(limits(Some(10_000), None, None));
+        let mut writer = Tracked::new(Vec::new(), tracker.start(A).unwrap());
+        let start = std::time::Instant::now();
+        // the first chunk goes out at once, the second waits for its debt
+        writer.write_all(&[0; 1000]).await.unwrap();
+        writer.write_all(&[0; 1000]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let (content, _) = writer.into_parts();
+        assert_eq!(content.len(), 2000);
+    }
+}
@@ -1,12 +1,137 @@
 //! This module serves common utility functions and types
 
-use std::{net::IpAddr, sync::LazyLock};
-
-static LOCAL_IP : LazyLock<IpAddr> = LazyLock::new(|| {
-    let ip = local_ip_address::local_ip().expect("Could not get local ip address");
-    log::info!("local ip: {}", ip);
-    ip
-});
-pub fn get_local_ip() -> IpAddr {
-    *LOCAL_IP
+use std::{io::Read, net::IpAddr, path::Path};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use sha2::{Digest, Sha256};
+
+use crate::config::NetworkConfig;
+
+/// An address assigned to one of the host's network interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalAddr {
+    pub interface: String,
+    pub ip: IpAddr,
+}
+
+impl LocalAddr {
+    fn is_link_local(&self) -> bool {
+        match self.ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => ip.is_unicast_link_local(),
+        }
+    }
+}
+
+/// Lists the addresses of every interface selected by the network config.
+/// Loopback addresses are skipped. The list is read fresh on every call,
+/// so it follows interfaces coming and going while the server runs.
+pub fn list_local_addrs(conf: &NetworkConfig) -> Vec<LocalAddr> {
+    let interfaces = match local_ip_address::list_afinet_netifas() {
+        Ok(list) => list,
+        Err(e) => {
+            log::error!("Could not list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    interfaces
+        .into_iter()
+        .map(|(interface, ip)| LocalAddr { interface, ip })
+        .filter(|addr| !addr.ip.is_loopback())
+        .filter(|addr| conf.ipv6 || addr.ip.is_ipv4())
+        .filter(|addr| conf.interfaces.is_empty() || conf.interfaces.contains(&addr.interface))
+        .filter(|addr| !conf.exclude_interfaces.contains(&addr.interface))
+        .collect()
+}
+
+/// Returns the addresses that should be advertised to clients.
+/// Configured addresses take precedence over detected ones.
+pub fn advertised_ips(conf: &NetworkConfig) -> Vec<IpAddr> {
+    if !conf.addresses.is_empty() {
+        return conf.addresses.clone();
+    }
+    list_local_addrs(conf).into_iter().map(|addr| addr.ip).collect()
+}
+
+/// Picks the single address used where only one fits, such as the QR code.
+/// Prefers the address of the default route, then any IPv4, then anything else.
+/// Returns `None` when no usable address exists.
+pub fn get_local_ip(conf: &NetworkConfig) -> Option<IpAddr> {
+    if let Some(ip) = conf
+        .addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(conf.addresses.first())
+    {
+        return Some(*ip);
+    }
+    let addrs = list_local_addrs(conf);
+    if let Ok(default) = local_ip_address::local_ip()
+        && addrs.iter().any(|addr| addr.ip == default)
+    {
+        return Some(default);
+    }
+    addrs
+        .iter()
+        .filter(|addr| !addr.is_link_local())
+        .find(|addr| addr.ip.is_ipv4())
+        .or_else(|| addrs.iter().find(|addr| !addr.is_link_local()))
+        .map(|addr| addr.ip)
+}
+
+/// The network an interface address belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalSubnet {
+    pub interface: String,
+    pub ip: IpAddr,
+    pub net: IpNet,
+}
+
+/// Lists the subnets of every interface, loopback included.
+/// Read fresh on every call, like [`list_local_addrs`].
+pub fn list_local_subnets() -> Vec<LocalSubnet> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(list) => list,
+        Err(e) => {
+            log::error!("Could not list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    interfaces
+        .into_iter()
+        .filter_map(|interface| {
+            let (ip, net) = match interface.addr {
+                if_addrs::IfAddr::V4(addr) => {
+                    (IpAddr::V4(addr.ip), IpNet::V4(Ipv4Net::new(addr.ip, addr.prefixlen).ok()?))
+                }
+                if_addrs::IfAddr::V6(addr) => {
+                    (IpAddr::V6(addr.ip), IpNet::V6(Ipv6Net::new(addr.ip, addr.prefixlen).ok()?))
+                }
+            };
+            Some(LocalSubnet {
+                interface: interface.name,
+                ip,
+                net: net.trunc(),
+            })
+        })
+        .collect()
+}
+
+/// Hex encoded SHA-256 of a file's content. Blocking.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    sha256_reader(std::fs::File::open(path)?)
+}
+
+/// Hex encoded SHA-256 of everything `reader` yields. Blocking.
+pub fn sha256_reader(mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
 }
@@ -0,0 +1,374 @@
+// End-to-end encryption of uploads, used by upload.html and share.html
+//
+// An encrypted file is MAGIC, a random nonce prefix, then chunks of CHUNK_SIZE
+// bytes sealed with ChaCha20-Poly1305 (RFC 8439). The nonce of a chunk is the
+// prefix, its index and whether it is the last one, so chunks can not be
+// dropped or reordered.
+//
+// The cipher is implemented here rather than taken from WebCrypto, which
+// browsers only offer over HTTPS and on localhost, not on a plain HTTP LAN.
+// Files written by older versions used AES-256-GCM under the magic "LSE2E001",
+// they still decrypt where WebCrypto is available.
+const E2E = (() => {
+    const MAGIC        = new TextEncoder().encode("LSE2E002");
+    const MAGIC_GCM    = new TextEncoder().encode("LSE2E001");
+    const PREFIX_SIZE  = 7;
+    const CHUNK_SIZE   = 64 * 1024;
+    const TAG_SIZE     = 16;
+    const SEALED_SIZE  = CHUNK_SIZE + TAG_SIZE;
+    const HEADER_SIZE  = MAGIC.length + PREFIX_SIZE;
+    // both ends hold the whole file in memory, see README
+    const MAX_SIZE     = 256 * 1024 * 1024;
+
+    // --- ChaCha20 (RFC 8439, section 2.3) ---
+
+    const SIGMA = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+    function readU32(bytes, offset) {
+        return (bytes[offset] | (bytes[offset + 1] << 8) | (bytes[offset + 2] << 16) | (bytes[offset + 3] << 24)) >>> 0;
+    }
+
+    function rotl(value, bits) {
+        return (value << bits) | (value >>> (32 - bits));
+    }
+
+    // Cipher state for one key and nonce, yields 64 byte keystream blocks
+    class ChaCha20 {
+        constructor(key, nonce) {
+            this.state = new Uint32Array(16);
+            this.words = new Uint32Array(16);
+            this.block = new Uint8Array(this.words.buffer);
+            this.state.set(SIGMA);
+            for (let i = 0; i < 8; i++) this.state[4 + i] = readU32(key, i * 4);
+            for (let i = 0; i < 3; i++) this.state[13 + i] = readU32(nonce, i * 4);
+        }
+
+        keystream(counter) {
+            const s = this.state;
+            let x0 = s[0], x1 = s[1], x2 = s[2], x3 = s[3];
+            let x4 = s[4], x5 = s[5], x6 = s[6], x7 = s[7];
+            let x8 = s[8], x9 = s[9], x10 = s[10], x11 = s[11];
+            let x12 = counter, x13 = s[13], x14 = s[14], x15 = s[15];
+            for (let round = 0; round < 10; round++) {
+                // columns
+                x0 = (x0 + x4) | 0; x12 = rotl(x12 ^ x0, 16); x8 = (x8 + x12) | 0; x4 = rotl(x4 ^ x8, 12);
+                x0 = (x0 + x4) | 0; x12 = rotl(x12 ^ x0, 8); x8 = (x8 + x12) | 0; x4 = rotl(x4 ^ x8, 7);
+                x1 = (x1 + x5) | 0; x13 = rotl(x13 ^ x1, 16); x9 = (x9 + x13) | 0; x5 = rotl(x5 ^ x9, 12);
+                x1 = (x1 + x5) | 0; x13 = rotl(x13 ^ x1, 8); x9 = (x9 + x13) | 0; x5 = rotl(x5 ^ x9, 7);
+                x2 = (x2 + x6) | 0; x14 = rotl(x14 ^ x2, 16); x10 = (x10 + x14) | 0; x6 = rotl(x6 ^ x10, 12);
+                x2 = (x2 + x6) | 0; x14 = rotl(x14 ^ x2, 8); x10 = (x10 + x14) | 0; x6 = rotl(x6 ^ x10, 7);
+                x3 = (x3 + x7) | 0; x15 = rotl(x15 ^ x3, 16); x11 = (x11 + x15) | 0; x7 = rotl(x7 ^ x11, 12);
+                x3 = (x3 + x7) | 0; x15 = rotl(x15 ^ x3, 8); x11 = (x11 + x15) | 0; x7 = rotl(x7 ^ x11, 7);
+                // diagonals
+                x0 = (x0 + x5) | 0; x15 = rotl(x15 ^ x0, 16); x10 = (x10 + x15) | 0; x5 = rotl(x5 ^ x10, 12);
+                x0 = (x0 + x5) | 0; x15 = rotl(x15 ^ x0, 8); x10 = (x10 + x15) | 0; x5 = rotl(x5 ^ x10, 7);
+                x1 = (x1 + x6) | 0; x12 = rotl(x12 ^ x1, 16); x11 = (x11 + x12) | 0; x6 = rotl(x6 ^ x11, 12);
+                x1 = (x1 + x6) | 0; x12 = rotl(x12 ^ x1, 8); x11 = (x11 + x12) | 0; x6 = rotl(x6 ^ x11, 7);
+                x2 = (x2 + x7) | 0; x13 = rotl(x13 ^ x2, 16); x8 = (x8 + x13) | 0; x7 = rotl(x7 ^ x8, 12);
+                x2 = (x2 + x7) | 0; x13 = rotl(x13 ^ x2, 8); x8 = (x8 + x13) | 0; x7 = rotl(x7 ^ x8, 7);
+                x3 = (x3 + x4) | 0; x14 = rotl(x14 ^ x3, 16); x9 = (x9 + x14) | 0; x4 = rotl(x4 ^ x9, 12);
+                x3 = (x3 + x4) | 0; x14 = rotl(x14 ^ x3, 8); x9 = (x9 + x14) | 0; x4 = rotl(x4 ^ x9, 7);
+            }
+            // the words are little endian on every platform browsers run on
+            const w = this.words;
+            w[0] = x0 + s[0]; w[1] = x1 + s[1]; w[2] = x2 + s[2]; w[3] = x3 + s[3];
+            w[4] = x4 + s[4]; w[5] = x5 + s[5]; w[6] = x6 + s[6]; w[7] = x7 + s[7];
+            w[8] = x8 + s[8]; w[9] = x9 + s[9]; w[10] = x10 + s[10]; w[11] = x11 + s[11];
+            w[12] = x12 + counter; w[13] = x13 + s[13]; w[14] = x14 + s[14]; w[15] = x15 + s[15];
+            return this.block;
+        }
+
+        // XORs `input` with the keystream starting at block `counter`
+        xor(input, counter) {
+            const output = new Uint8Array(input.length);
+            for (let offset = 0; offset < input.length; offset += 64, counter++) {
+                const block = this.keystream(counter);
+                const end = Math.min(64, input.length - offset);
+                for (let i = 0; i < end; i++) output[offset + i] = input[offset + i] ^ block[i];
+            }
+            return output;
+        }
+    }
+
+    // --- Poly1305 (RFC 8439, section 2.5) ---
+    // Numbers are kept in ten 13 bit limbs, so the products fit in a double
+    // and the carries in 32 bit integers.
+
+    const LIMB = 8192;
+
+    // ten limbs of the little endian number in bytes[offset..offset + 17]
+    function toLimbs(bytes, offset, limbs) {
+        for (let i = 0; i < 10; i++) {
+            const bit = i * 13;
+            const at = offset + (bit >>> 3);
+            const value = bytes[at] | (bytes[at + 1] << 8) | (bytes[at + 2] << 16);
+            limbs[i] = (value >>> (bit & 7)) & 0x1fff;
+        }
+    }
+
+    class Poly1305 {
+        constructor(otk) {
+            // r is clamped, s is added at the end
+            const r = new Uint8Array(19);
+            r.set(otk.subarray(0, 16));
+            r[3] &= 15; r[7] &= 15; r[11] &= 15; r[15] &= 15;
+            r[4] &= 252; r[8] &= 252; r[12] &= 252;
+            const limbs = new Int32Array(10);
+            toLimbs(r, 0, limbs);
+            // row i holds the limbs of r that meet h[j] in limb i of h * r,
+            // the ones past 2^130 folded back in as 5
+            this.rows = new Int32Array(100);
+            for (let i = 0; i < 10; i++) {
+                for (let j = 0; j < 10; j++) {
+                    this.rows[i * 10 + j] = j <= i ? limbs[i - j] : 5 * limbs[i + 10 - j];
+                }
+            }
+            this.h = new Int32Array(10);
+            this.d = new Int32Array(10);
+            this.s = otk.slice(16, 32);
+        }
+
+        // Adds a full 16 byte block at `bytes[offset]`
+        block(bytes, offset) {
+            const { h, d, rows } = this;
+            const t0 = bytes[offset] | (bytes[offset + 1] << 8);
+            const t1 = bytes[offset + 2] | (bytes[offset + 3] << 8);
+            const t2 = bytes[offset + 4] | (bytes[offset + 5] << 8);
+            const t3 = bytes[offset + 6] | (bytes[offset + 7] << 8);
+            const t4 = bytes[offset + 8] | (bytes[offset + 9] << 8);
+            const t5 = bytes[offset + 10] | (bytes[offset + 11] << 8);
+            const t6 = bytes[offset + 12] | (bytes[offset + 13] << 8);
+            const t7 = bytes[offset + 14] | (bytes[offset + 15] << 8);
+            h[0] += t0 & 0x1fff;
+            h[1] += ((t0 >>> 13) | (t1 << 3)) & 0x1fff;
+            h[2] += ((t1 >>> 10) | (t2 << 6)) & 0x1fff;
+            h[3] += ((t2 >>> 7) | (t3 << 9)) & 0x1fff;
+            h[4] += ((t3 >>> 4) | (t4 << 12)) & 0x1fff;
+            h[5] += (t4 >>> 1) & 0x1fff;
+            h[6] += ((t4 >>> 14) | (t5 << 2)) & 0x1fff;
+            h[7] += ((t5 >>> 11) | (t6 << 5)) & 0x1fff;
+            h[8] += ((t6 >>> 8) | (t7 << 8)) & 0x1fff;
+            // with the bit above the block
+            h[9] += (t7 >>> 5) | (1 << 11);
+            let carry = 0;
+            for (let i = 0; i < 10; i++) {
+                const row = i * 10;
+                // carried halfway, so the sums stay below 2^32
+                let sum = carry;
+                for (let j = 0; j < 5; j++) sum += h[j] * rows[row + j];
+                carry = sum >>> 13;
+                sum &= 0x1fff;
+                for (let j = 5; j < 10; j++) sum += h[j] * rows[row + j];
+                carry += sum >>> 13;
+                d[i] = sum & 0x1fff;
+            }
+            carry = d[0] + carry * 5;
+            d[0] = carry & 0x1fff;
+            d[1] += carry >>> 13;
+            h.set(d);
+        }
+
+        // Adds `bytes`, zero padded to whole blocks
+        update(bytes) {
+            const whole = bytes.length - (bytes.length % 16);
+            for (let offset = 0; offset < whole; offset += 16) this.block(bytes, offset);
+            if (whole < bytes.length) {
+                const last = new Uint8Array(16);
+                last.set(bytes.subarray(whole));
+                this.block(last, 0);
+            }
+        }
+
+        finish() {
+            const h = this.h;
+            // fully carried, then reduced below 2^130 - 5
+            for (let round = 0; round < 2; round++) {
+                for (let i = 0; i < 10; i++) {
+                    const carry = Math.floor(h[i] / LIMB);
+                    h[i] -= carry * LIMB;
+                    if (i < 9) h[i + 1] += carry;
+                    else h[0] += carry * 5;
+                }
+            }
+            const g = new Float64Array(10);
+            let carry = 5;
+            for (let i = 0; i < 10; i++) {
+                const value = h[i] + carry;
+                carry = Math.floor(value / LIMB);
+                g[i] = value - carry * LIMB;
+            }
+            // h + 5 reached 2^130, so h >= 2^130 - 5
+            const reduced = carry ? g : h;
+            const tag = new Uint8Array(16);
+            let acc = 0, bits = 0, at = 0;
+            for (let i = 0; i < 10 && at < 16; i++) {
+                acc |= reduced[i] << bits;
+                bits += 13;
+                while (bits >= 8 && at < 16) {
+                    tag[at++] = acc & 0xff;
+                    acc >>>= 8;
+                    bits -= 8;
+                }
+            }
+            let sum = 0;
+            for (let i = 0; i < 16; i++) {
+                sum += tag[i] + this.s[i];
+                tag[i] = sum & 0xff;
+                sum >>>= 8;
+            }
+            return tag;
+        }
+    }
+
+    // --- AEAD (RFC 8439, section 2.8), without additional data ---
+
+    function lengthBlock(length) {
+        const block = new Uint8Array(16);
+        new DataView(block.buffer).setUint32(8, length % 0x100000000, true);
+        new DataView(block.buffer).setUint32(12, Math.floor(length / 0x100000000), true);
+        return block;
+    }
+
+    function authenticate(cipher, sealed) {
+        const poly = new Poly1305(cipher.keystream(0).slice(0, 32));
+        poly.update(sealed);
+        poly.update(lengthBlock(sealed.length));
+        return poly.finish();
+    }
+
+    function seal(key, nonce, plain) {
+        const cipher = new ChaCha20(key, nonce);
+        const sealed = new Uint8Array(plain.length + TAG_SIZE);
+        sealed.set(cipher.xor(plain, 1));
+        sealed.set(authenticate(cipher, sealed.subarray(0, plain.length)), plain.length);
+        return sealed;
+    }
+
+    function open(key, nonce, sealed) {
+        if (sealed.length < TAG_SIZE) throw new E2EError("The encrypted file is truncated.");
+        const cipher = new ChaCha20(key, nonce);
+        const body = sealed.subarray(0, sealed.length - TAG_SIZE);
+        const expected = authenticate(cipher, body);
+        const tag = sealed.subarray(sealed.length - TAG_SIZE);
+        let diff = 0;
+        for (let i = 0; i < TAG_SIZE; i++) diff |= expected[i] ^ tag[i];
+        if (diff !== 0) throw new E2EError("Wrong key, or the file was modified.");
+        return cipher.xor(body, 1);
+    }
+
+    // --- file format ---
+
+    class E2EError extends Error {}
+
+    function chunkNonce(prefix, index, last) {
+        const nonce = new Uint8Array(12);
+        nonce.set(prefix);
+        new DataView(nonce.buffer).setUint32(PREFIX_SIZE, index);
+        nonce[11] = last ? 1 : 0;
+        return nonce;
+    }
+
+    function base64url(bytes) {
+        return btoa(String.fromCharCode(...bytes))
+            .replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+    }
+
+    // The key from a share link, throws when it is not one
+    function keyFromLink(text) {
+        const base64 = text.trim().replace(/-/g, "+").replace(/_/g, "/");
+        const key = Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+        if (key.length !== 32) throw new E2EError("The key is not valid, check that the link is complete.");
+        return key;
+    }
+
+    function concat(a, b) {
+        const joined = new Uint8Array(a.length + b.length);
+        joined.set(a);
+        joined.set(b, a.length);
+        return joined;
+    }
+
+    const nextFrame = () => new Promise((resolve) => setTimeout(resolve, 0));
+
+    // Returns the ciphertext and the key for the share link
+    async function encryptFile(file, onProgress) {
+        if (file.size > MAX_SIZE) {
+            throw new E2EError(`End-to-end encrypted files can be at most ${MAX_SIZE / 1024 / 1024} MiB.`);
+        }
+        const key    = crypto.getRandomValues(new Uint8Array(32));
+        const prefix = crypto.getRandomValues(new Uint8Array(PREFIX_SIZE));
+        const parts  = [MAGIC, prefix];
+        const count  = Math.max(1, Math.ceil(file.size / CHUNK_SIZE));
+        for (let index = 0; index < count; index++) {
+            const plain = await file.slice(index * CHUNK_SIZE, (index + 1) * CHUNK_SIZE).arrayBuffer();
+            parts.push(seal(key, chunkNonce(prefix, index, index === count - 1), new Uint8Array(plain)));
+            onProgress(Math.round(((index + 1) / count) * 100));
+            // keeps the page responsive
+            if (index % 16 === 15) await nextFrame();
+        }
+        return { data: new Blob(parts), key: base64url(key) };
+    }
+
+    // Opens chunks of files written by older versions
+    async function gcmOpener(key) {
+        if (!window.crypto?.subtle) {
+            throw new E2EError("This file was encrypted by an older version, decrypting it needs HTTPS or localhost.");
+        }
+        const gcmKey = await crypto.subtle.importKey("raw", key, "AES-GCM", false, ["decrypt"]);
+        return async (iv, sealed) => {
+            try {
+                return new Uint8Array(await crypto.subtle.decrypt({ name: "AES-GCM", iv }, gcmKey, sealed));
+            } catch (_) {
+                throw new E2EError("Wrong key, or the file was modified.");
+            }
+        };
+    }
+
+    // Decrypts the response as it arrives, returns the plaintext parts
+    async function decryptResponse(res, key, onProgress) {
+        const total  = Number(res.headers.get("Content-Length")) || 0;
+        const reader = res.body.getReader();
+        const parts  = [];
+        let buffer   = new Uint8Array(0);
+        let received = 0;
+        let prefix   = null;
+        let opener   = null;
+        let index    = 0;
+
+        const openChunk = async (sealed, last) => {
+            parts.push(await opener(chunkNonce(prefix, index++, last), sealed));
+        };
+
+        for (;;) {
+            const { done, value } = await reader.read();
+            if (done) break;
+            received += value.length;
+            buffer = concat(buffer, value);
+            if (total) onProgress(Math.round((received / total) * 100));
+
+            if (!prefix) {
+                if (buffer.length < HEADER_SIZE) continue;
+                const magic = buffer.subarray(0, MAGIC.length);
+                if (MAGIC.every((b, i) => magic[i] === b)) {
+                    opener = async (nonce, sealed) => open(key, nonce, sealed);
+                } else if (MAGIC_GCM.every((b, i) => magic[i] === b)) {
+                    opener = await gcmOpener(key);
+                } else {
+                    throw new E2EError("This file is not end-to-end encrypted.");
+                }
+                prefix = buffer.slice(MAGIC.length, HEADER_SIZE);
+                buffer = buffer.slice(HEADER_SIZE);
+            }
+            // a full chunk is only known not to be the last once more follows
+            while (buffer.length > SEALED_SIZE) {
+                await openChunk(buffer.slice(0, SEALED_SIZE), false);
+                buffer = buffer.slice(SEALED_SIZE);
+            }
+        }
+        if (!prefix) throw new E2EError("The encrypted file is truncated.");
+        await openChunk(buffer, true);
+        return parts;
+    }
+
+    return { MAX_SIZE, E2EError, seal, open, encryptFile, decryptResponse, keyFromLink };
+})();
@@ -0,0 +1,94 @@
+//! Many requests using the pooled database at once must neither fail with
+//! SQLITE_BUSY nor lose writes
+
+use chrono::Utc;
+use localshare::{
+    config::Config,
+    fm::{AsyncFileManager, FileManager, record::Record},
+};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+/// Clients inserting and reading at the same time, well above the pool size
+const CLIENTS: usize = 64;
+const RECORDS_PER_CLIENT: usize = 20;
+
+fn record(uuid: Uuid, n: usize) -> Record {
+    Record {
+        uuid,
+        uploaded_at: Utc::now(),
+        name: format!("file-{}.txt", n),
+        description: None,
+        author: "test".to_string(),
+        sha256: None,
+        size: Some(n as u64),
+        stored_size: Some(n as u64),
+        encoding: None,
+        encrypted: false,
+        e2e: false,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn parallel_inserts_and_reads() {
+    let workdir = tempfile::tempdir().unwrap();
+    let fm = AsyncFileManager::new(FileManager::new(workdir.path(), Config::default()).unwrap());
+
+    let mut clients = JoinSet::new();
+    for client in 0..CLIENTS {
+        let fm = fm.clone();
+        clients.spawn(async move {
+            for i in 0..RECORDS_PER_CLIENT {
+                let n = client * RECORDS_PER_CLIENT + i;
+                let uuid = Uuid::new_v4();
+                fm.insert_record(record(uuid, n)).await?;
+                let found = fm
+                    .get_record_by_uuid(uuid)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("record {} was not found", n))?;
+                assert_eq!(found.name, format!("file-{}.txt", n));
+                // listing takes a read transaction while the others write
+                if i % 5 == 0 {
+                    fm.get_all_records().await?;
+                }
+            }
+            anyhow::Ok(())
+        });
+    }
+    while let Some(joined) = clients.join_next().await {
+        if let Err(e) = joined.unwrap() {
+            panic!("database access failed under load: {:#}", e);
+        }
+    }
+    let records = fm.get_all_records().await.unwrap();
+    assert_eq!(records.len(), CLIENTS * RECORDS_PER_CLIENT);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn parallel_pending_uploads() {
+    let workdir = tempfile::tempdir().unwrap();
+    let fm = AsyncFileManager::new(FileManager::new(workdir.path(), Config::default()).unwrap());
+
+    let mut clients = JoinSet::new();
+    for n in 0..CLIENTS {
+        let fm = fm.clone();
+        clients.spawn(async move {
+            let uuid = Uuid::new_v4();
+            fm.insert_pending_record(record(uuid, n)).await?;
+            assert!(fm.get_record_by_uuid(uuid).await?.is_none());
+            // every other upload is never committed, as if the server crashed
+            if n % 2 == 0 {
+                fm.commit_record(uuid).await?;
+                assert!(fm.get_record_by_uuid(uuid).await?.is_some());
+            }
+            anyhow::Ok(())
+        });
+    }
+    while let Some(joined) = clients.join_next().await {
+        if let Err(e) = joined.unwrap() {
+            panic!("database access failed under load: {:#}", e);
+        }
+    }
+    assert_eq!(fm.get_all_records().await.unwrap().len(), CLIENTS / 2);
+    assert_eq!(fm.pending_records().await.unwrap().len(), CLIENTS / 2);
+}
@@ -0,0 +1,317 @@
+//! Every storage backend must behave the same, see `localshare::storage::Storage`.
+//! The S3 backend runs against a small in-process stand-in for an object store.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use localshare::{
+    config::S3Config,
+    storage::{LocalStorage, S3Storage, Storage},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const BUCKET: &str = "files";
+const PREFIX: &str = "localshare/";
+
+async fn read_all(
+    storage: &dyn Storage,
+    key: &str,
+    range: Option<std::ops::Range<u64>>,
+) -> io::Result<Vec<u8>> {
+    let mut reader = storage.get(key, range).await?;
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).await?;
+    Ok(content)
+}
+
+fn kind<T>(result: io::Result<T>) -> io::ErrorKind {
+    result.map(|_| ()).unwrap_err().kind()
+}
+
+/// The behaviour every backend shares
+async fn contract(storage: &dyn Storage) {
+    let content: Vec<u8> = (0..100_000u32).map(|n| (n % 251) as u8).collect();
+
+    assert_eq!(
+        storage.put("a", &mut content.as_slice()).await.unwrap(),
+        content.len() as u64
+    );
+    assert_eq!(
+        storage.stat("a").await.unwrap().unwrap().size,
+        content.len() as u64
+    );
+    assert_eq!(read_all(storage, "a", None).await.unwrap(), content);
+
+    // ranges
+    assert_eq!(
+        read_all(storage, "a", Some(0..10)).await.unwrap(),
+        content[..10]
+    );
+    assert_eq!(
+        read_all(storage, "a", Some(500..70_000)).await.unwrap(),
+        content[500..70_000]
+    );
+    assert_eq!(
+        read_all(storage, "a", Some(99_999..100_000)).await.unwrap(),
+        content[99_999..]
+    );
+    assert!(
+        read_all(storage, "a", Some(42..42))
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // replacing
+    assert_eq!(storage.put("a", &mut &b"short"[..]).await.unwrap(), 5);
+    assert_eq!(read_all(storage, "a", None).await.unwrap(), b"short");
+    assert_eq!(storage.stat("a").await.unwrap().unwrap().size, 5);
+
+    // empty blobs
+    assert_eq!(storage.put("empty", &mut &b""[..]).await.unwrap(), 0);
+    assert_eq!(storage.stat("empty").await.unwrap().unwrap().size, 0);
+    assert!(read_all(storage, "empty", None).await.unwrap().is_empty());
+
+    // put_file moves the file into the storage
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("staged");
+    std::fs::write(&path, b"from a file").unwrap();
+    assert_eq!(storage.put_file("b", &path).await.unwrap(), 11);
+    assert!(!path.exists());
+    assert_eq!(read_all(storage, "b", None).await.unwrap(), b"from a file");
+
+    let mut keys = storage.list().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["a", "b", "empty"]);
+
+    storage.delete("a").await.unwrap();
+    assert!(storage.stat("a").await.unwrap().is_none());
+
+    // missing keys
+    assert_eq!(
+        kind(read_all(storage, "a", None).await),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(kind(storage.delete("a").await), io::ErrorKind::NotFound);
+    assert!(storage.stat("missing").await.unwrap().is_none());
+
+    // keys must not leave the storage
+    for key in ["", "../a", "/etc/passwd", "a/../../b", "a\\b"] {
+        assert_eq!(
+            kind(storage.stat(key).await),
+            io::ErrorKind::InvalidInput,
+            "{:?}",
+            key
+        );
+        assert_eq!(
+            kind(storage.put(key, &mut &b"x"[..]).await),
+            io::ErrorKind::InvalidInput,
+            "{:?}",
+            key
+        );
+    }
+
+    let mut keys = storage.list().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["b", "empty"]);
+}
+
+#[tokio::test]
+async fn local_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    contract(&LocalStorage::new(dir.path().to_path_buf())).await;
+}
+
+#[tokio::test]
+async fn s3_storage() {
+    let store = FakeS3::start().await;
+    contract(&store.storage()).await;
+    // nothing is stored outside the prefix
+    assert!(
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .all(|k| k.starts_with(PREFIX))
+    );
+}
+
+#[tokio::test]
+async fn s3_download_cut_off() {
+    let store = FakeS3::start().await;
+    let storage = store.storage();
+    let content = vec![7u8; 200_000];
+    storage.put("a", &mut content.as_slice()).await.unwrap();
+
+    store.cut_off.store(true, Ordering::SeqCst);
+    for range in [None, Some(1000..150_000)] {
+        let result = read_all(&storage, "a", range.clone()).await;
+        assert!(
+            result.is_err(),
+            "a cut off download of {:?} read as complete",
+            range
+        );
+    }
+}
+
+/// Just enough of the S3 API for [`S3Storage`], one request per connection
+struct FakeS3 {
+    addr: SocketAddr,
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    /// Sends only half of every body, then closes the connection
+    cut_off: Arc<AtomicBool>,
+}
+
+impl FakeS3 {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let store = Self {
+            addr: listener.local_addr().unwrap(),
+            objects: Default::default(),
+            cut_off: Default::default(),
+        };
+        let (objects, cut_off) = (store.objects.clone(), store.cut_off.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, objects.clone(), cut_off.clone()));
+            }
+        });
+        store
+    }
+
+    fn storage(&self) -> S3Storage {
+        let config = S3Config {
+            endpoint: format!("http://{}", self.addr),
+            bucket: BUCKET.to_string(),
+            prefix: PREFIX.to_string(),
+            path_style: true,
+            ..Default::default()
+        };
+        S3Storage::with_credentials(&config, "access", "secret").unwrap()
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    cut_off: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (
+        parts.next().unwrap_or_default().to_string(),
+        parts.next().unwrap_or_default().to_string(),
+    );
+    let (mut length, mut range) = (0, None);
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().unwrap(),
+                "range" => range = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let key = path
+        .strip_prefix(&format!("/{}/", BUCKET))
+        .unwrap_or_default()
+        .to_string();
+    let (status, body) = {
+        let mut objects = objects.lock().unwrap();
+        match method.as_str() {
+            "GET" if key.is_empty() && query.contains("list-type=2") => {
+                let prefix = query
+                    .split('&')
+                    .find_map(|p| p.strip_prefix("prefix="))
+                    .unwrap_or_default()
+                    .replace("%2F", "/");
+                (200, list(&objects, &prefix))
+            }
+            "PUT" => {
+                objects.insert(key, body);
+                (200, Vec::new())
+            }
+            "GET" | "HEAD" => match objects.get(&key) {
+                Some(content) => match range.as_deref().and_then(|r| r.strip_prefix("bytes=")) {
+                    Some(range) => {
+                        let (first, last) = range.split_once('-').unwrap();
+                        let first: usize = first.parse().unwrap();
+                        let end = match last {
+                            "" => content.len(),
+                            last => (last.parse::<usize>().unwrap() + 1).min(content.len()),
+                        };
+                        (206, content[first..end].to_vec())
+                    }
+                    None => (200, content.clone()),
+                },
+                None => (404, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+            },
+            "DELETE" => {
+                objects.remove(&key);
+                (204, Vec::new())
+            }
+            _ => (405, Vec::new()),
+        }
+    };
+
+    let stream = stream.get_mut();
+    let head = format!(
+        "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nETag: \"0\"\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    if method != "HEAD" {
+        let sent = match cut_off.load(Ordering::SeqCst) {
+            true => body.len() / 2,
+            false => body.len(),
+        };
+        stream.write_all(&body[..sent]).await?;
+    }
+    stream.shutdown().await
+}
+
+fn list(objects: &BTreeMap<String, Vec<u8>>, prefix: &str) -> Vec<u8> {
+    let contents: String = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .map(|(key, content)| {
+            format!(
+                "<Contents><Key>{}</Key><LastModified>2026-01-01T00:00:00.000Z</LastModified>\
+                 <ETag>\"0\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                key,
+                content.len()
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>1000</MaxKeys>\
+         <IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+        BUCKET, prefix, contents
+    )
+    .into_bytes()
+}